@@ -1,13 +1,21 @@
 use tokio::net::UdpSocket;
 use tokio::time::{Duration, Instant};
 use rand::Rng;
-use rong_shared::model::{NetworkPacket, ClientMessage, ServerMessage, PlayerId, GameState, Position, MovementPacket, Movement};
+use rong_shared::model::{NetworkPacket, ClientMessage, ServerMessage, PlayerId, GameStatus, GameSnapshot, EntityId, Position, MovementData, Movement, Ack, SessionToken, PROTOCOL_VERSION};
 use rong_shared::error::ClientError;
-use bincode;
+use rong_shared::transport::{encode_datagram, now_millis, DefaultCodec, NetworkConditions, Reassembler, ReliableEndpoint, SimulatedTransport, SnapshotDecoder, Transport, RECEIVE_BUFFER_SIZE};
+use std::net::SocketAddr;
 use log::{info, error};
 
 const SERVER_ADDR: &str = "127.0.0.1:2906";
 const MOVE_INTERVAL: Duration = Duration::from_millis(16); // 60Hz update frequency
+// Until the match starts we have nothing else to send, this keeps the server from timing us out
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Everything we send goes through the simulated network, perfect unless flags say otherwise
+type Socket = SimulatedTransport<UdpSocket>;
+// Numbers our packets, acks the server's, and resends our reliable messages
+type Endpoint = ReliableEndpoint<ClientMessage, ServerMessage>;
 
 struct PlayerState {
     id: PlayerId,
@@ -16,6 +24,7 @@ struct PlayerState {
 
 struct GameData {
     player: PlayerState,
+    session: Option<SessionToken>,
     opponent_position: Position,
     ball_position: Position,
     ball_dx: f32,
@@ -27,40 +36,67 @@ struct GameData {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
+    // --latency, --jitter, --loss, --duplicate and --reorder simulate a bad network
+    let conditions = NetworkConditions::from_args(std::env::args())?;
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.connect(SERVER_ADDR).await?;
+    let socket = SimulatedTransport::new(socket, conditions);
 
     info!("Connected to server at {}", SERVER_ADDR);
-    send_message(&socket, ClientMessage::Connect()).await?;
-    info!("Sent Connect message");
+    let mut endpoint = Endpoint::default();
+    send_message(&socket, &mut endpoint, ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: format!("mock-{}", env!("CARGO_PKG_VERSION")),
+    }).await?;
+    info!("Sent Hello for protocol version {}", PROTOCOL_VERSION);
+    // Reliable and ordered, so the server only sees it after our Hello
+    send_message(&socket, &mut endpoint, ClientMessage::JoinQueue).await?;
+    info!("Sent JoinQueue message");
 
     let mut game_data = GameData {
-        player: PlayerState { id: PlayerId::Player1, position: (0.5, 0.0) },
+        player: PlayerState { id: PlayerId::PLAYER_1, position: (0.5, 0.0) },
+        session: None,
         opponent_position: (0.5, 1.0),
         ball_position: (0.5, 0.5),
         ball_dx: 0.0,
         ball_dy: 0.0,
         last_ball_position: (0.5, 0.5),
     };
-    let mut game_state = GameState::WaitingForPlayers;
+    let mut game_state = GameStatus::WaitingForPlayers;
     let mut last_move_time = Instant::now();
     let mut last_update_time = Instant::now();
-    let mut sequence_number = 0;
+    let mut last_sent = Instant::now();
+    let mut input_id = 0;
 
-    let mut last_state: Option<GameState> = None;
+    let mut last_state: Option<GameStatus> = None;
+    let mut reassembler = Reassembler::new();
+    let mut snapshots = SnapshotDecoder::new();
 
     loop {
-        let mut buffer = [0; 1024];
+        let mut buffer = [0; RECEIVE_BUFFER_SIZE];
         tokio::select! {
             // Handle incoming messages
             Ok((amt, addr)) = socket.recv_from(&mut buffer) => {
-                let buf = &mut [0; 1024][..amt];
-                match bincode::deserialize::<NetworkPacket<ServerMessage>>(buf) {
-                    Ok(packet) => {
+                match reassembler.decode::<DefaultCodec, NetworkPacket<ServerMessage>>(&buffer[..amt], Instant::now().into_std()) {
+                    Ok(Some(packet)) => {
                         info!("*Client* Received packet from {}: {:?}", addr, packet);
-                        handle_server_message(packet.get_payload(), &mut game_state, &mut game_data);
+                        for packet in endpoint.receive(packet) {
+                            let message = match packet.into_payload() {
+                                // Expand deltas and ack them so the server can use them as baselines
+                                ServerMessage::GameUpdate(snapshot) => match snapshots.decode(snapshot) {
+                                    Some((id, data)) => {
+                                        send_message(&socket, &mut endpoint, ClientMessage::SnapshotAck(id)).await?;
+                                        ServerMessage::GameUpdate(GameSnapshot::Full { id, data })
+                                    }
+                                    None => continue,
+                                },
+                                message => message,
+                            };
+                            handle_server_message(&message, &mut game_state, &mut game_data);
+                        }
                     },
-                    Err(e) => error!("Failed to deserialize packet: {:?}", e),
+                    Ok(None) => {}, // Waiting on more fragments
+                    Err(e) => error!("Failed to decode packet from {}: {}", addr, e),
                 }
             }
 
@@ -75,11 +111,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     last_update_time = now;
                 }
 
+                for packet in endpoint.retransmit(now_millis(), now.into_std()) {
+                    send_packet(&socket, &packet).await?;
+                }
+                if endpoint.has_failed() {
+                    // The server is stuck waiting for a message it will never get
+                    error!("Server stopped acknowledging our messages, giving up");
+                    return Ok(());
+                }
+
                 // Send periodic moves if the game has started
-                if game_state == GameState::GameStarted {
+                if game_state == GameStatus::GameStarted {
                     if last_move_time.elapsed() >= MOVE_INTERVAL {
-                        send_smart_move(&socket, &game_data, &mut sequence_number).await?;
+                        send_smart_move(&socket, &mut endpoint, &game_data, &mut input_id).await?;
                         last_move_time = Instant::now();
+                        last_sent = last_move_time;
                     }
                     info!("Current game state: {:?}", game_state);
                 } else {
@@ -87,6 +133,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         info!("Current game state: {:?}", game_state);
                         last_state = Some(game_state);
                     }
+                    if last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                        send_message(&socket, &mut endpoint, ClientMessage::KeepAlive).await?;
+                        last_sent = Instant::now();
+                    }
                 }
             }
         }
@@ -95,35 +145,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn handle_server_message(
     msg: &ServerMessage,
-    game_state: &mut GameState,
+    game_state: &mut GameStatus,
     game_data: &mut GameData,
 ) {
     match msg {
-        ServerMessage::PlayerJoined(id) => {
+        ServerMessage::Welcome { protocol_version } => {
+            info!("Handshake complete, protocol version {}", protocol_version);
+        }
+        ServerMessage::Success(Ack::AddedToQueue(session)) => {
+            game_data.session = Some(*session);
+            info!("Joined queue");
+        }
+        ServerMessage::GameFound(id) => {
             game_data.player.id = *id;
-            game_data.player.position.1 = if *id == PlayerId::Player1 { 0.9 } else { 0.1 };
-            info!("Assigned as {:?}", id);
+            game_data.player.position.1 = if *id == PlayerId::PLAYER_1 { 0.9 } else { 0.1 };
+            info!("Matched as {:?}", id);
         }
-        ServerMessage::GameStateChange(new_state) => {
-            if new_state == &GameState::GameStarted {
-                info!("&*****************&");
-                info!("Game started RECEIVED");
-                info!("&*****************&");
+        ServerMessage::GameUpdate(GameSnapshot::Full { data, .. }) => {
+            let new_state = data.get_game_status();
+            if new_state != *game_state {
+                if new_state == GameStatus::GameStarted {
+                    info!("&*****************&");
+                    info!("Game started RECEIVED");
+                    info!("&*****************&");
+                }
+                *game_state = new_state;
+                info!("Game state changed to {:?}", new_state);
             }
 
-            *game_state = new_state.clone();
-            info!("Game state changed to {:?}", new_state);
-        }
-        ServerMessage::PositionUpdate(positions) => {
-            let (player1, player2, ball) = positions.get_payload();
-            if game_data.player.id == PlayerId::Player1 {
-                game_data.player.position = *player1;
-                game_data.opponent_position = *player2;
-            } else {
-                game_data.player.position = *player2;
-                game_data.opponent_position = *player1;
+            for entity in data.get_positions().get_entities() {
+                match entity.get_id() {
+                    EntityId::Player(id) if id == game_data.player.id => game_data.player.position = entity.get_position(),
+                    EntityId::Player(_) => game_data.opponent_position = entity.get_position(),
+                    EntityId::Ball => game_data.ball_position = entity.get_position(),
+                }
             }
-            game_data.ball_position = *ball;
             info!(
                 "Updated game state: Player at ({:.2}, {:.2}), Opponent at ({:.2}, {:.2}), Ball at ({:.2}, {:.2})",
                 game_data.player.position.0, game_data.player.position.1, 
@@ -131,15 +187,12 @@ fn handle_server_message(
                 game_data.ball_position.0, game_data.ball_position.1
             );
         }
-        ServerMessage::ScoreUpdate(scores) => {
-            let (score1, score2) = scores.get_payload();
-            info!("Score updated: {} - {}", score1, score2);
-        }
-        ServerMessage::Ack(msg) => {
-            info!("Server acknowledgement: {}", msg);
-        }
         ServerMessage::Error(error) => {
-            error!("Server error: {:?}", error);
+            error!("Server error {}: {}", error.code(), error);
+        }
+        ServerMessage::Disconnect { reason } => {
+            game_data.session = None;
+            error!("Disconnected by server: {:?}", reason);
         }
         _ => {
             info!("Unhandled message: {:?}", msg);
@@ -147,17 +200,26 @@ fn handle_server_message(
     }
 }
 
-async fn send_message(socket: &UdpSocket, message: ClientMessage) -> Result<(), ClientError> {
-    let packet = NetworkPacket::new(0, 0, message); // TODO: Implement proper sequence number and timestamp
-    let serialized = bincode::serialize(&packet)?;
-    socket.send(&serialized).await?;
+fn server_addr() -> SocketAddr {
+    SERVER_ADDR.parse().expect("SERVER_ADDR is a valid address")
+}
+
+async fn send_message(socket: &Socket, endpoint: &mut Endpoint, message: ClientMessage) -> Result<(), ClientError> {
+    let packet = endpoint.send(message, now_millis(), Instant::now().into_std());
+    send_packet(socket, &packet).await
+}
+
+async fn send_packet(socket: &Socket, packet: &NetworkPacket<ClientMessage>) -> Result<(), ClientError> {
+    let serialized = encode_datagram::<DefaultCodec, _>(packet)?;
+    socket.send_to(&serialized, server_addr()).await?;
     Ok(())
 }
 
 async fn send_smart_move(
-    socket: &UdpSocket,
+    socket: &Socket,
+    endpoint: &mut Endpoint,
     game_data: &GameData,
-    sequence_number: &mut u32,
+    input_id: &mut u32,
 ) -> Result<(), ClientError> {
     let target_x = predict_ball_position(game_data);
     let distance = target_x - game_data.player.position.0;
@@ -170,12 +232,16 @@ async fn send_smart_move(
         Movement::Down
     };
 
-    let movement_packet = MovementPacket::new(game_data.player.id, movement);
-    *sequence_number += 1;
-    let message = ClientMessage::MovementCommand(movement_packet);
-    let packet = NetworkPacket::new(*sequence_number, 0, message); // TODO: Implement proper timestamp
-    let serialized = bincode::serialize(&packet)?;
-    socket.send(&serialized).await?;
+    let Some(session) = game_data.session else {
+        return Ok(()); // Not joined yet
+    };
+    *input_id += 1;
+    let message = ClientMessage::MovementInput(MovementData::new(
+        session,
+        *input_id,
+        movement.clone(),
+    ));
+    send_message(socket, endpoint, message).await?;
     info!("Sent movement: {:?}", movement);
     Ok(())
 }
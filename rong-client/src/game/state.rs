@@ -1,12 +1,15 @@
 use super::{Ball, Opponent, Player};
-use crate::constants::{BALL_RADIUS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::constants::{SCREEN_HEIGHT, SCREEN_WIDTH};
 use crate::network::Server;
 use crate::ui::{PixelText, TitleBall, TitleText};
 use log::{error, info};
 use macroquad::audio::{play_sound, PlaySoundParams, Sound};
 use macroquad::prelude::*;
 use rong_shared::error::ClientError;
-use rong_shared::model::{GameState, Movement, PlayerId, Position, ServerMessage};
+use rong_shared::model::{
+    Ack, DisconnectReason, EntityId, GameEvent, GameSnapshot, GameStatus, GameUpdateData, Movement,
+    ServerMessage,
+};
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum ClientState {
@@ -28,12 +31,10 @@ pub struct Game {
     ball: Ball,
     pub server: Server,
     pub client_state: ClientState,
-    server_game_state: GameState,
+    server_game_state: GameStatus,
     score: (u8, u8),
     collision_sound: Sound,
     score_sound: Sound,
-    last_ball_position: Position,
-    last_ball_direction: (f32, f32),
     title_text: TitleText,
     join_game_text: PixelText,
     exit_text: PixelText,
@@ -41,6 +42,7 @@ pub struct Game {
     title_ball: TitleBall,
     debug_mode: bool,
     title_bounds: [Rect; 3],
+    error_message: Option<String>,
 }
 
 impl Game {
@@ -101,12 +103,10 @@ impl Game {
             opponent,
             ball,
             client_state: ClientState::TitleScreen,
-            server_game_state: GameState::WaitingForPlayers,
+            server_game_state: GameStatus::WaitingForPlayers,
             score: (0, 0),
             collision_sound,
             score_sound,
-            last_ball_position: (0.5, 0.5),
-            last_ball_direction: (0.0, 0.0),
             title_text,
             join_game_text,
             exit_text,
@@ -114,10 +114,15 @@ impl Game {
             title_ball: TitleBall::new(SCREEN_WIDTH / 2.0, SCREEN_HEIGHT / 2.0),
             debug_mode: false,
             title_bounds,
+            error_message: None,
         }
     }
 
     pub fn update_state(&mut self) -> Result<(), ClientError> {
+        if is_key_pressed(KeyCode::F3) {
+            self.toggle_debug_mode();
+        }
+
         match self.client_state {
             ClientState::TitleScreen => {
                 if is_key_pressed(KeyCode::Up) || is_key_pressed(KeyCode::Down) {
@@ -131,11 +136,22 @@ impl Game {
                     match self.selected_option {
                         TitleOption::JoinGame => {
                             info!("Player selected Join Game");
-                            self.server.send_connect()?;
-                            self.client_state = ClientState::WaitingForPlayers;
+                            match self.server.join_queue() {
+                                Ok(()) => {
+                                    self.error_message = None;
+                                    self.client_state = ClientState::WaitingForPlayers;
+                                }
+                                Err(ClientError::Server(e)) => {
+                                    error!("Server rejected connection: {}", e);
+                                    self.error_message = Some(e.to_string());
+                                }
+                                Err(e) => return Err(e),
+                            }
                         }
                         TitleOption::Exit => {
                             info!("Player selected Exit");
+                            // process::exit skips destructors, so say goodbye here
+                            self.server.disconnect(DisconnectReason::Quit)?;
                             std::process::exit(0);
                         }
                     }
@@ -172,61 +188,73 @@ impl Game {
         while let Some(message) = self.server.receive()? {
             info!("Received message from server: {:?}", message);
             match message {
-                ServerMessage::GameStateChange(new_state) => {
-                    info!("Game state changed to {:?}", new_state);
-                    self.server_game_state = new_state;
-                    match new_state {
-                        GameState::GameStarted => {
-                            info!("Game started!");
-                            self.client_state = ClientState::Playing;
-                        }
-                        GameState::GameOver => self.client_state = ClientState::GameOver,
-                        _ => {}
-                    }
-                }
-                ServerMessage::PositionUpdate(positions) => {
-                    let (player1, player2, ball) = positions.get_payload();
-                    info!(
-                        "Position update: Player1 {:?}, Player2 {:?}, Ball {:?}",
-                        player1, player2, ball
-                    );
-                    if self.player.id == PlayerId::Player1 {
-                        self.player.set_position((player1.0, player1.1));
-                        self.opponent.set_position((player2.0, player2.1));
-                    } else {
-                        self.player.set_position((player2.0, player2.1));
-                        self.opponent.set_position((player2.0, player2.1));
-                    }
-                    self.check_collision(*ball);
-                    self.ball.set_position((ball.0, ball.1));
+                ServerMessage::GameFound(player_id) => {
+                    info!("Matched as {:?}", player_id);
+                    self.player.id = player_id;
                 }
-                ServerMessage::ScoreUpdate(scores) => {
-                    let (score1, score2) = scores.get_payload();
-                    info!("Score update: {} - {}", score1, score2);
-                    if self.score != (score1, score2) {
-                        self.play_score_sound();
-                        self.score = (score1, score2);
-                    }
+                // Server::receive expands deltas, only full updates get here
+                ServerMessage::GameUpdate(GameSnapshot::Full { data, .. }) => {
+                    self.apply_game_update(&data)
                 }
-                ServerMessage::PlayerJoined(player_id) => {
-                    info!("Player joined: {:?}", player_id);
-                    // Update UI to show player connection status if needed
+                ServerMessage::GameUpdate(GameSnapshot::Delta(_)) => {}
+                ServerMessage::Success(Ack::AddedToQueue(_)) => {
+                    info!("Added to the queue");
                 }
-                ServerMessage::PlayerLeft(player_id) => {
-                    info!("Player left: {:?}", player_id);
-                    // Update UI to show player connection status if needed
+                ServerMessage::Success(Ack::RemovedFromQueue) => {
+                    info!("Removed from the queue");
                 }
-                ServerMessage::Ack(msg) => {
-                    info!("Server acknowledgement: {}", msg);
+                ServerMessage::Welcome { protocol_version } => {
+                    info!("Server welcomed us on protocol {}", protocol_version);
                 }
                 ServerMessage::Error(error) => {
-                    error!("Server error: {:?}", error);
+                    error!("Server error {}: {}", error.code(), error);
                 }
+                ServerMessage::GameEvent(event) => self.handle_game_event(event),
+                ServerMessage::Disconnect { reason } => {
+                    error!("Disconnected by server: {:?}", reason);
+                    self.error_message = Some(format!("Disconnected: {:?}", reason));
+                    self.client_state = ClientState::TitleScreen;
+                }
+                // Answered inside Server, never handed out
+                ServerMessage::KeyExchange(_)
+                | ServerMessage::Ping
+                | ServerMessage::Pong(_)
+                | ServerMessage::KeepAlive => {}
             }
         }
         Ok(())
     }
 
+    fn apply_game_update(&mut self, update: &GameUpdateData) {
+        let status = update.get_game_status();
+        if status != self.server_game_state {
+            info!("Game state changed to {:?}", status);
+            self.server_game_state = status;
+            match status {
+                GameStatus::GameStarted => {
+                    info!("Game started!");
+                    self.client_state = ClientState::Playing;
+                }
+                GameStatus::GameOver => self.client_state = ClientState::GameOver,
+                GameStatus::WaitingForPlayers => {}
+            }
+        }
+
+        let positions = update.get_positions();
+        for entity in positions.get_entities() {
+            match entity.get_id() {
+                EntityId::Player(id) if id == self.player.id => {
+                    self.player.set_position(entity.get_position())
+                }
+                EntityId::Player(_) => self.opponent.set_position(entity.get_position()),
+                EntityId::Ball => self.ball.set_position(entity.get_position()),
+            }
+        }
+
+        let (score1, score2) = update.get_scores().get_classic();
+        self.score = (score1.get_points(), score2.get_points());
+    }
+
     pub fn move_player_left(&mut self) -> Result<(), ClientError> {
         self.server.send_movement(Movement::Down)?;
         Ok(())
@@ -237,28 +265,16 @@ impl Game {
         Ok(())
     }
 
-    fn check_collision(&mut self, new_ball_pos: Position) {
-        let (old_x, old_y) = self.last_ball_position;
-        let new_direction = (new_ball_pos.0 - old_x, new_ball_pos.1 - old_y);
-
-        if (new_direction.0 * self.last_ball_direction.0 < 0.0)
-            || (new_direction.1 * self.last_ball_direction.1 < 0.0)
-        {
-            if !self.is_top_bottom_collision(old_y, new_ball_pos.1) {
-                self.play_collision_sound();
-            }
+    // Effects follow the server's events rather than guessing from positions
+    fn handle_game_event(&mut self, event: GameEvent) {
+        info!("Game event: {:?}", event);
+        match event {
+            GameEvent::PaddleHit(_) | GameEvent::WallBounce(_) => self.play_collision_sound(),
+            GameEvent::PointScored(_) => self.play_score_sound(),
+            GameEvent::ServeStarted(_) | GameEvent::MatchPoint(_) => {}
+            // The server ends the match when the opponent leaves
+            GameEvent::PlayerLeft { .. } => self.client_state = ClientState::GameOver,
         }
-
-        self.last_ball_position = new_ball_pos;
-        self.last_ball_direction = new_direction;
-    }
-
-    fn is_top_bottom_collision(&self, old_y: f32, new_y: f32) -> bool {
-        let top_boundary = BALL_RADIUS / SCREEN_HEIGHT;
-        let bottom_boundary = 1.0 - BALL_RADIUS / SCREEN_HEIGHT;
-
-        (old_y > top_boundary && new_y <= top_boundary)
-            || (old_y < bottom_boundary && new_y >= bottom_boundary)
     }
 
     fn play_collision_sound(&self) {
@@ -297,6 +313,17 @@ impl Game {
                 self.exit_text
                     .draw(self.selected_option == TitleOption::Exit);
 
+                if let Some(message) = &self.error_message {
+                    let text_dimensions = measure_text(message, None, 20, 1.0);
+                    draw_text(
+                        message,
+                        (SCREEN_WIDTH - text_dimensions.width) / 2.0,
+                        SCREEN_HEIGHT - 30.0,
+                        20.0,
+                        RED,
+                    );
+                }
+
                 if self.debug_mode {
                     for bound in self.title_bounds.iter() {
                         draw_rectangle_lines(bound.x, bound.y, bound.w, bound.h, 2.0, LIME);
@@ -345,6 +372,26 @@ impl Game {
 
         if self.debug_mode {
             draw_text(&format!("FPS: {}", get_fps()), 10.0, 10.0, 20.0, GREEN);
+
+            // Nothing to show until the first Pong comes back
+            let clock = self.server.get_clock_sync();
+            if let (Some(rtt), Some(offset)) = (clock.rtt(), clock.offset_millis()) {
+                draw_text(
+                    &format!("RTT: {}ms offset: {}ms", rtt.as_millis(), offset),
+                    10.0,
+                    30.0,
+                    20.0,
+                    GREEN,
+                );
+            }
+            let dropped = self.server.get_dropped_packets();
+            draw_text(
+                &format!("Dropped: {}", dropped.total()),
+                10.0,
+                50.0,
+                20.0,
+                GREEN,
+            );
         }
     }
 
@@ -352,8 +399,8 @@ impl Game {
         info!("Resetting game state");
         self.score = (0, 0);
         self.client_state = ClientState::WaitingForPlayers;
-        self.server_game_state = GameState::WaitingForPlayers;
-        self.server.send_connect()?;
+        self.server_game_state = GameStatus::WaitingForPlayers;
+        self.server.join_queue()?;
         Ok(())
     }
 
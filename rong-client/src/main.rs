@@ -9,6 +9,7 @@ use macroquad::audio::{load_sound_from_bytes, play_sound, stop_sound, PlaySoundP
 use macroquad::prelude::*;
 use network::Server;
 use rong_shared::model::PlayerId;
+use rong_shared::transport::NetworkConditions;
 
 const BALL_COLLISION_SOUND_BYTES: &[u8] = include_bytes!("../assets/wii_game_disc_case_close.wav");
 const SCORE_SOUND_BYTES: &[u8] = include_bytes!("../assets/coin_collect_eleven.wav");
@@ -24,10 +25,13 @@ async fn main() {
     let menu_music = load_sound_from_bytes(MENU_MUSIC_BYTES).await.unwrap();
 
     // Set up structs for game objects
-    let player = Player::new(PlayerId::Player1);
+    let player = Player::new(PlayerId::PLAYER_1);
     let opponent = Opponent::new();
     let ball = Ball::new();
-    let server = Server::new()
+    // --latency, --jitter, --loss, --duplicate and --reorder simulate a bad network
+    let conditions = NetworkConditions::from_args(std::env::args())
+        .unwrap_or_else(|e| panic!("Bad network condition flags: {}", e));
+    let server = Server::with_conditions(conditions)
         .map_err(|e| panic!("Failed to create server: {:?}", e))
         .unwrap();
 
@@ -1,49 +1,169 @@
-use bincode;
 use rong_shared::error::ClientError;
+use rong_shared::error::DecodeError;
 use rong_shared::model::{
-    ClientMessage, Movement, MovementPacket, NetworkPacket, PlayerId, ServerMessage,
+    Ack, ClientMessage, DisconnectReason, GameSnapshot, Movement, MovementData, NetworkPacket,
+    PlayerId, ServerMessage, SessionToken, PROTOCOL_VERSION,
 };
+use rong_shared::transport::{
+    now_millis, ClockSync, DefaultCodec, DroppedPackets, Fragmenter, LinkSimulator,
+    NetworkConditions, Reassembler, ReliableEndpoint, SnapshotDecoder, WireCodec,
+    RECEIVE_BUFFER_SIZE,
+};
+#[cfg(feature = "secure")]
+use rong_shared::transport::{open_plain, KeyExchange, Opened, Role, SecureChannel};
+use std::collections::VecDeque;
 use std::io::{ErrorKind, Read, Write};
 use std::net::UdpSocket;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const SERVER_ADDR: &str = "127.0.0.1:2906";
+const CLIENT_BUILD: &str = env!("CARGO_PKG_VERSION");
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// While in a match, go no longer than this without sending the server something
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(500);
+// A server silent for this long is taken to be gone
+const SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(feature = "secure")]
+const KEY_EXCHANGE_RETRY: Duration = Duration::from_millis(250);
 
 pub struct Server {
     socket: UdpSocket,
-    sequence_number: u32,
+    reliability: ReliableEndpoint<ClientMessage, ServerMessage>,
+    // Messages released by the reliability layer but not yet handed out
+    inbox: VecDeque<ServerMessage>,
+    snapshots: SnapshotDecoder,
+    dropped: DroppedPackets,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    // Issued by the server when we join, attached to everything we send after
+    session: Option<SessionToken>,
+    next_input_id: u32,
+    clock: ClockSync,
+    last_ping: Option<Instant>,
+    last_sent: Instant,
+    last_heard: Instant,
+    // Holds outgoing datagrams back when running over a simulated bad network
+    link: Option<LinkSimulator>,
+    // Our half of the key exchange until the server's key arrives, then the sealed channel
+    #[cfg(feature = "secure")]
+    key_exchange: Option<KeyExchange>,
+    #[cfg(feature = "secure")]
+    secure: Option<SecureChannel>,
     pub player_id: Option<PlayerId>,
 }
 
 impl Server {
-    pub fn new() -> Result<Self, ClientError> {
+    // What we send goes through a simulated network with these conditions
+    pub fn with_conditions(conditions: NetworkConditions) -> Result<Self, ClientError> {
         let socket = UdpSocket::bind("127.0.0.1:0")?;
         socket.set_nonblocking(true)?;
         socket.connect(SERVER_ADDR)?;
 
         Ok(Server {
             socket,
-            sequence_number: 0,
+            reliability: ReliableEndpoint::default(),
+            inbox: VecDeque::new(),
+            snapshots: SnapshotDecoder::new(),
+            dropped: DroppedPackets::default(),
+            fragmenter: Fragmenter::new(),
+            reassembler: Reassembler::new(),
+            session: None,
+            next_input_id: 0,
+            clock: ClockSync::new(),
+            last_ping: None,
+            last_sent: Instant::now(),
+            last_heard: Instant::now(),
+            link: (!conditions.is_perfect()).then(|| LinkSimulator::new(conditions)),
+            #[cfg(feature = "secure")]
+            key_exchange: None,
+            #[cfg(feature = "secure")]
+            secure: None,
             player_id: None,
         })
     }
 
-    pub fn send_connect(&mut self) -> Result<(), ClientError> {
-        let message: ClientMessage = ClientMessage::Connect();
+    pub fn send_hello(&mut self) -> Result<(), ClientError> {
+        let message = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: CLIENT_BUILD.to_string(),
+        };
         self.send_packet(message)?;
 
-        // Wait for response with a timeout
+        // Wait for the server to accept or reject our protocol version
         let start_time = std::time::Instant::now();
         while start_time.elapsed() < Duration::from_secs(5) {
             match self.receive() {
-                Ok(Some(ServerMessage::PlayerJoined(id))) => {
-                    self.player_id = Some(id);
+                Ok(Some(ServerMessage::Welcome { protocol_version })) => {
+                    println!("Handshake complete, protocol version {}", protocol_version);
+                    #[cfg(feature = "secure")]
+                    self.exchange_keys()?;
+                    return Ok(());
+                }
+                Ok(Some(ServerMessage::Error(error))) => {
+                    return Err(ClientError::Server(error));
+                }
+                Ok(Some(msg)) => {
+                    println!("Unexpected message: {:?}", msg);
+                }
+                Ok(None) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ClientError::Io("Handshake timeout".to_string()))
+    }
+
+    // The key exchange is unreliable, so keep offering our key until the
+    // server answers with its own
+    #[cfg(feature = "secure")]
+    fn exchange_keys(&mut self) -> Result<(), ClientError> {
+        let exchange = KeyExchange::new();
+        let message = ClientMessage::KeyExchange(exchange.public_key());
+        self.key_exchange = Some(exchange);
 
-                    self.send_packet(ClientMessage::ConnectionAck())?;
-                    println!("Connection to server, awknowledged");
+        let start_time = std::time::Instant::now();
+        let mut last_sent: Option<Instant> = None;
+        while start_time.elapsed() < Duration::from_secs(5) {
+            if last_sent.map_or(true, |sent| sent.elapsed() >= KEY_EXCHANGE_RETRY) {
+                self.send_packet(message.clone())?;
+                last_sent = Some(Instant::now());
+            }
 
+            match self.receive() {
+                Ok(Some(msg)) => {
+                    println!("Unexpected message: {:?}", msg);
+                }
+                Ok(None) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+
+            if self.secure.is_some() {
+                println!("Key exchange complete, traffic is now sealed");
+                return Ok(());
+            }
+        }
+        Err(ClientError::Io("Key exchange timeout".to_string()))
+    }
+
+    // Joins the matchmaking queue. Which side we play arrives later, in GameFound
+    pub fn join_queue(&mut self) -> Result<(), ClientError> {
+        self.send_hello()?;
+        self.send_packet(ClientMessage::JoinQueue)?;
+
+        // Wait for the server to hand us a session
+        let start_time = std::time::Instant::now();
+        while start_time.elapsed() < Duration::from_secs(5) {
+            match self.receive() {
+                Ok(Some(ServerMessage::Success(Ack::AddedToQueue(_)))) => {
+                    println!("Joined the queue, waiting for an opponent");
                     return Ok(());
                 }
+                Ok(Some(ServerMessage::Error(error))) => {
+                    return Err(ClientError::Server(error));
+                }
                 Ok(Some(msg)) => {
                     println!("Unexpected message: {:?}", msg);
                 }
@@ -54,40 +174,251 @@ impl Server {
                 Err(e) => return Err(e),
             }
         }
-        Err(ClientError::Io("Connection timeout".to_string()))
+        Err(ClientError::Io("Join queue timeout".to_string()))
     }
 
     pub fn receive(&mut self) -> Result<Option<ServerMessage>, ClientError> {
-        let mut buf = [0; 1024];
-        match self.socket.recv(&mut buf) {
-            Ok(amt) => {
-                let packet: NetworkPacket<ServerMessage> = bincode::deserialize(&buf[..amt])?;
-                let message = packet.get_payload().clone();
-                if let ServerMessage::PlayerJoined(id) = &message {
-                    self.player_id = Some(*id);
+        self.flush_link()?;
+        self.resend_pending()?;
+        self.ping_if_due()?;
+        self.keep_alive_if_due()?;
+        self.check_server_timeout()?;
+
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+        loop {
+            if let Some(message) = self.inbox.pop_front() {
+                match &message {
+                    ServerMessage::GameFound(id) => self.player_id = Some(*id),
+                    ServerMessage::Success(Ack::AddedToQueue(session)) => {
+                        self.session = Some(*session)
+                    }
+                    ServerMessage::Disconnect { .. } => self.session = None,
+                    _ => {}
+                }
+                return Ok(Some(message));
+            }
+
+            match self.socket.recv(&mut buf) {
+                Ok(amt) => {
+                    self.last_heard = Instant::now();
+                    let packet: NetworkPacket<ServerMessage> = match self
+                        .reassembler
+                        .receive(&buf[..amt], Instant::now())
+                        .and_then(|body| match body {
+                            Some(body) => self.open_body(&body),
+                            None => Ok(None),
+                        }) {
+                        Ok(Some(packet)) => packet,
+                        // Waiting on the rest of a fragmented message
+                        Ok(None) => continue,
+                        Err(DecodeError::Header(e)) => {
+                            self.dropped.record(&e);
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    for packet in self.reliability.receive(packet) {
+                        let timestamp = packet.get_timestamp();
+                        match packet.into_payload() {
+                            ServerMessage::GameUpdate(snapshot) => self.apply_snapshot(snapshot)?,
+                            ServerMessage::Ping => {
+                                self.send_packet(ClientMessage::Pong(timestamp))?
+                            }
+                            ServerMessage::Pong(ping_time) => {
+                                self.clock.record(ping_time, timestamp, now_millis())
+                            }
+                            ServerMessage::KeepAlive => {}
+                            message => self.inbox.push_back(message),
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(ClientError::Io(e.to_string())),
+            }
+        }
+    }
+
+    #[cfg(not(feature = "secure"))]
+    fn open_body(
+        &mut self,
+        body: &[u8],
+    ) -> Result<Option<NetworkPacket<ServerMessage>>, DecodeError> {
+        DefaultCodec::decode(body).map(Some)
+    }
+
+    #[cfg(feature = "secure")]
+    fn open_body(
+        &mut self,
+        body: &[u8],
+    ) -> Result<Option<NetworkPacket<ServerMessage>>, DecodeError> {
+        let opened = match self.secure.as_mut() {
+            Some(channel) => channel.open::<DefaultCodec, ServerMessage>(body)?,
+            None => open_plain::<DefaultCodec, ServerMessage>(body)?,
+        };
+
+        match opened {
+            Opened::Packet(packet) => Ok(Some(packet)),
+            Opened::KeyExchange(server_key) => {
+                // Later replies are just the server answering our retries
+                if let Some(exchange) = self.key_exchange.take() {
+                    self.secure = Some(exchange.finish(Role::Client, server_key)?);
                 }
-                Ok(Some(message))
+                Ok(None)
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
-            Err(e) => Err(ClientError::Io(e.to_string())),
         }
     }
 
-    pub fn send_movement(&mut self, movement: Movement) -> Result<(), ClientError> {
-        if let Some(player_id) = self.player_id {
-            let movement_packet = MovementPacket::new(player_id, movement);
-            let message = ClientMessage::MovementCommand(movement_packet);
-            self.send_packet(message)
+    // Expands deltas so callers always see full updates, and acks the result
+    // so the server can use it as the next baseline
+    fn apply_snapshot(&mut self, snapshot: GameSnapshot) -> Result<(), ClientError> {
+        if let Some((id, data)) = self.snapshots.decode(snapshot) {
+            self.send_packet(ClientMessage::SnapshotAck(id))?;
+            self.inbox
+                .push_back(ServerMessage::GameUpdate(GameSnapshot::Full { id, data }));
+        }
+        Ok(())
+    }
+
+    // Keeps the round trip and clock offset estimates fresh. Secure builds
+    // wait for the key exchange, the server drops anything else unsealed
+    fn ping_if_due(&mut self) -> Result<(), ClientError> {
+        #[cfg(feature = "secure")]
+        if self.secure.is_none() {
+            return Ok(());
+        }
+        if self
+            .last_ping
+            .is_some_and(|sent| sent.elapsed() < PING_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_ping = Some(Instant::now());
+        self.send_packet(ClientMessage::Ping)
+    }
+
+    fn keep_alive_if_due(&mut self) -> Result<(), ClientError> {
+        if self.session.is_some() && self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.send_packet(ClientMessage::KeepAlive)?;
+        }
+        Ok(())
+    }
+
+    // Reported like a Disconnect from the server, so callers handle both the same way.
+    // A reliable message the server never acked leaves it stuck, which is as good as gone
+    fn check_server_timeout(&mut self) -> Result<(), ClientError> {
+        let timed_out = self.last_heard.elapsed() > SERVER_TIMEOUT || self.reliability.has_failed();
+        if self.session.is_some() && timed_out {
+            // In case the server can still hear us, so it forgets us too
+            self.disconnect(DisconnectReason::TimedOut)?;
+            self.reset_link();
+            self.inbox.push_back(ServerMessage::Disconnect {
+                reason: DisconnectReason::TimedOut,
+            });
+        }
+        Ok(())
+    }
+
+    // Joining again starts from a clean slate, same as the server's side
+    fn reset_link(&mut self) {
+        self.reliability = ReliableEndpoint::default();
+        self.snapshots = SnapshotDecoder::new();
+        #[cfg(feature = "secure")]
+        {
+            self.key_exchange = None;
+            self.secure = None;
+        }
+    }
+
+    // Tells the server we're leaving so our opponent hears about it straight
+    // away, instead of when we time out
+    pub fn disconnect(&mut self, reason: DisconnectReason) -> Result<(), ClientError> {
+        if self.session.take().is_some() {
+            self.send_packet(ClientMessage::Disconnect { reason })?;
+        }
+        Ok(())
+    }
+
+    // Round trip time and clock offset, the offset being the server's clock minus ours
+    pub fn get_clock_sync(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    // Re-send reliable messages the server hasn't acknowledged yet
+    fn resend_pending(&mut self) -> Result<(), ClientError> {
+        for packet in self.reliability.retransmit(now_millis(), Instant::now()) {
+            self.send_datagrams(&packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_dropped_packets(&self) -> DroppedPackets {
+        self.dropped
+    }
+
+    // Returns the input's id, which GameUpdateData echoes once the server applied it
+    pub fn send_movement(&mut self, movement: Movement) -> Result<u32, ClientError> {
+        if let Some(session) = self.session {
+            let input_id = self.next_input_id;
+            self.next_input_id = self.next_input_id.wrapping_add(1);
+            let message =
+                ClientMessage::MovementInput(MovementData::new(session, input_id, movement));
+            self.send_packet(message)?;
+            Ok(input_id)
         } else {
-            Err(ClientError::Io("Player ID not set".to_string()))
+            Err(ClientError::Io("No session yet".to_string()))
         }
     }
 
     fn send_packet(&mut self, message: ClientMessage) -> Result<(), ClientError> {
-        self.sequence_number += 1;
-        let packet = NetworkPacket::new(self.sequence_number, 0, message);
-        let serialized = bincode::serialize(&packet)?;
-        self.socket.send(&serialized)?;
+        let packet = self.reliability.send(message, now_millis(), Instant::now());
+        self.send_datagrams(&packet)
+    }
+
+    // Large packets go out as several fragments
+    fn send_datagrams(&mut self, packet: &NetworkPacket<ClientMessage>) -> Result<(), ClientError> {
+        #[cfg(feature = "secure")]
+        let datagrams = match &self.secure {
+            Some(channel) => self
+                .fragmenter
+                .encode_body(&channel.seal::<DefaultCodec, _>(packet)?)?,
+            None => self.fragmenter.encode::<DefaultCodec, _>(packet)?,
+        };
+        #[cfg(not(feature = "secure"))]
+        let datagrams = self.fragmenter.encode::<DefaultCodec, _>(packet)?;
+
+        self.last_sent = Instant::now();
+        match self.link.as_mut() {
+            Some(link) => {
+                let server = self.socket.peer_addr()?;
+                for datagram in datagrams {
+                    link.push(&datagram, server, Instant::now());
+                }
+                self.flush_link()
+            }
+            None => {
+                for datagram in datagrams {
+                    self.socket.send(&datagram)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Sends whatever the simulated network is done delaying. Runs on every
+    // receive, so delays are only as precise as the frame rate
+    fn flush_link(&mut self) -> Result<(), ClientError> {
+        let Some(link) = self.link.as_mut() else {
+            return Ok(());
+        };
+        for (datagram, _) in link.pop_due(Instant::now()) {
+            self.socket.send(&datagram)?;
+        }
         Ok(())
     }
 }
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.disconnect(DisconnectReason::Quit);
+    }
+}
@@ -5,6 +5,8 @@ use rong_shared::model;
 // Constants for game dimensions and ball behavior
 const PLAYER_WIDTH: f32 = 0.125; // 12.5% of screen width
 const PLAYER_HEIGHT: f32 = 0.0167; // 1.67% of screen height
+
+// Speeds are per tick, see state::TICK
 const MAX_BALL_SPEED: f32 = 0.02; // Maximum ball speed to prevent tunneling
 const INITIAL_BALL_SPEED: f32 = 0.005; // Initial ball speed after reset
 
@@ -17,7 +19,8 @@ pub struct Ball {
 }
 
 impl Ball {
-    pub fn new() -> Self {
+    // Draws the first serve from rng, the match's own so it can be replayed
+    pub fn new(rng: &mut impl Rng) -> Self {
         let mut ball = Self {
             x: 0.5,
             y: 0.5,
@@ -25,14 +28,13 @@ impl Ball {
             dy: 0.0,
             radius: 0.01, // 1% of screen width/height
         };
-        ball.reset(rand::random::<u8>() % 2 + 1); // Randomly serve to player 1 or 2
+        let serve_to_player = rng.gen_range(1..=2); // Randomly serve to player 1 or 2
+        ball.reset(serve_to_player, rng);
         ball
     }
 
     // Reset the ball's position and set its initial trajectory
-    pub fn reset(&mut self, serve_to_player: u8) {
-        let mut rng = rand::thread_rng();
-
+    pub fn reset(&mut self, serve_to_player: u8, rng: &mut impl Rng) {
         // Reset position to center
         self.x = 0.5;
         self.y = 0.5;
@@ -60,13 +62,15 @@ impl Ball {
         (self.x, self.y)
     }
 
-    // Update the ball's position, checking for collisions
-    pub fn update_position(&mut self, players: &[Player]) {
+    // Move the ball by one tick, checking for collisions
+    // Returns the player whose paddle the ball bounced off, if any
+    pub fn update_position(&mut self, players: &[Player]) -> Option<model::PlayerId> {
         // Calculate the number of steps to move the ball
         // This helps prevent tunneling by ensuring small movements
         let steps = (self.dx.abs().max(self.dy.abs()) / 0.01).ceil() as i32;
         let step_x = self.dx / steps as f32;
         let step_y = self.dy / steps as f32;
+        let mut paddle_hit = None;
 
         // Move the ball step by step
         for _ in 0..steps {
@@ -79,6 +83,7 @@ impl Ball {
             for player in players {
                 if self.check_collision(new_x, new_y, player) {
                     self.handle_collision(player);
+                    paddle_hit = Some(player.get_id());
                     collision_occurred = true;
                     break;
                 }
@@ -90,6 +95,8 @@ impl Ball {
                 self.y = new_y.clamp(self.radius, 1.0 - self.radius);
             }
         }
+
+        paddle_hit
     }
 
     // Check if the ball collides with a player's paddle
@@ -173,9 +180,7 @@ impl Ball {
         }
     }
 
-    pub fn reset_velocity(&mut self, serve_to_player: u8) {
-        let mut rng = rand::thread_rng();
-
+    pub fn reset_velocity(&mut self, serve_to_player: u8, rng: &mut impl Rng) {
         // Randomize horizontal direction slightly
         let angle = rng.gen_range(-std::f32::consts::PI / 6.0..std::f32::consts::PI / 6.0);
 
@@ -192,10 +197,12 @@ impl Ball {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn test_ball_movement() {
-        let mut ball = Ball::new();
+        let mut ball = Ball::new(&mut StdRng::seed_from_u64(1));
         let initial_position = ball.get_position();
 
         // Simulate ball movement
@@ -207,9 +214,28 @@ mod tests {
         assert_ne!(initial_position, new_position, "Ball should have moved");
     }
 
+    #[test]
+    fn test_ball_reports_paddle_hit() {
+        let mut ball = Ball::new(&mut StdRng::seed_from_u64(1));
+        let mut player = Player::new(model::PlayerId::PLAYER_2, "127.0.0.1:0".parse().unwrap());
+        player.set_position(0.5, 0.9);
+
+        // Head down at the paddle. Collisions need movement on both axes
+        ball.set_position(0.5, 0.85);
+        ball.dx = 0.001;
+        ball.dy = 0.01;
+
+        let mut hit = None;
+        for _ in 0..10 {
+            hit = hit.or(ball.update_position(&[player.clone()]));
+        }
+        assert!(matches!(hit, Some(model::PlayerId::PLAYER_2)));
+        assert!(ball.dy < 0.0, "Ball should bounce back up");
+    }
+
     #[test]
     fn test_ball_wall_collision() {
-        let mut ball = Ball::new();
+        let mut ball = Ball::new(&mut StdRng::seed_from_u64(1));
 
         // Move ball to left wall
         ball.set_position(ball.radius, 0.5);
@@ -2,8 +2,246 @@ pub mod ball;
 pub mod player;
 pub mod state;
 
+use rong_shared::error::ServerError;
+use rong_shared::model::{GameStatus, PlayerId, SessionToken};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+// Sessions of ended matches remembered, see GameStateManager::find_by_session
+const MAX_ENDED_SESSIONS: usize = 256;
+
+// Server side only, clients find their match through their session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MatchId(u32);
+
+// What a match is, apart from how it's going. The seed and tick count are
+// enough to replay it given the same inputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchMetadata {
+    pub id: MatchId,
+    pub seed: u64,
+    pub ticks: u64,
+    pub status: GameStatus,
+}
+
+/*  Every match being played, each with its own State */
+#[derive(Default)]
 pub struct GameStateManager {
-    players: Vec<player::Player>,
-    balls: Vec<ball::Ball>,
-    state: state::State,
+    matches: HashMap<MatchId, state::State>,
+    // Where each seated player plays, so packets are routed without a scan.
+    // Filled from the players a match has when it's hosted
+    by_session: HashMap<SessionToken, MatchId>,
+    by_addr: HashMap<SocketAddr, MatchId>,
+    // Sessions from recently ended matches, oldest first, so a late packet
+    // is told its match is gone rather than that its session is unknown
+    ended_sessions: VecDeque<SessionToken>,
+    ended_lookup: HashSet<SessionToken>,
+    next_id: u32,
+}
+
+impl GameStateManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn host(&mut self, state: state::State) -> MatchId {
+        let id = MatchId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        for (addr, session) in state.players.get_sessions() {
+            self.by_session.insert(session, id);
+            self.by_addr.insert(addr, id);
+        }
+        self.matches.insert(id, state);
+        id
+    }
+
+    pub fn end_match(&mut self, id: MatchId) -> Option<state::State> {
+        // A player may already be seated in a newer match, leave that be
+        let mut ended = Vec::new();
+        self.by_session.retain(|&session, match_id| {
+            if *match_id == id {
+                ended.push(session);
+            }
+            *match_id != id
+        });
+        self.by_addr.retain(|_, match_id| *match_id != id);
+        for session in ended {
+            self.remember_ended(session);
+        }
+        self.matches.remove(&id)
+    }
+
+    fn remember_ended(&mut self, session: SessionToken) {
+        if self.ended_sessions.len() == MAX_ENDED_SESSIONS {
+            if let Some(oldest) = self.ended_sessions.pop_front() {
+                self.ended_lookup.remove(&oldest);
+            }
+        }
+        self.ended_sessions.push_back(session);
+        self.ended_lookup.insert(session);
+    }
+
+    pub fn get_match(&self, id: MatchId) -> Result<&state::State, ServerError> {
+        self.matches.get(&id).ok_or(ServerError::MatchNotFound)
+    }
+
+    pub fn get_match_mut(&mut self, id: MatchId) -> Result<&mut state::State, ServerError> {
+        self.matches.get_mut(&id).ok_or(ServerError::MatchNotFound)
+    }
+
+    pub fn get_metadata(&self, id: MatchId) -> Result<MatchMetadata, ServerError> {
+        self.get_match(id).map(|state| MatchMetadata {
+            id,
+            seed: state.get_seed(),
+            ticks: state.get_ticks(),
+            status: state.get_state(),
+        })
+    }
+
+    // Oldest first
+    pub fn get_match_ids(&self) -> Vec<MatchId> {
+        let mut ids: Vec<MatchId> = self.matches.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn get_match_count(&self) -> usize {
+        self.matches.len()
+    }
+
+    // The match and seat a packet speaks for, as long as its session and
+    // source address agree. MatchNotFound if the session's match has ended
+    pub fn find_by_session(
+        &self,
+        session: SessionToken,
+        addr: SocketAddr,
+    ) -> Result<(MatchId, PlayerId), ServerError> {
+        let Some(&id) = self.by_session.get(&session) else {
+            return Err(if self.ended_lookup.contains(&session) {
+                ServerError::MatchNotFound
+            } else {
+                ServerError::InvalidSession
+            });
+        };
+        let player_id = self
+            .get_match(id)?
+            .players
+            .resolve_session(session, addr)
+            .ok_or(ServerError::InvalidSession)?;
+        Ok((id, player_id))
+    }
+
+    pub fn find_by_addr(&self, addr: SocketAddr) -> Option<(MatchId, PlayerId)> {
+        let id = *self.by_addr.get(&addr)?;
+        let player_id = self.get_match(id).ok()?.players.get_player_id(addr)?;
+        Some((id, player_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::player::player_manager::PlayerManager;
+
+    async fn match_between(
+        first: SocketAddr,
+        second: SocketAddr,
+    ) -> (state::State, SessionToken, SessionToken) {
+        let mut state = state::State::new(PlayerManager::new());
+        let first = state.add_player(PlayerId::PLAYER_1, first).await.unwrap();
+        let second = state.add_player(PlayerId::PLAYER_2, second).await.unwrap();
+        (state, first, second)
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_session_to_the_right_match() {
+        let addrs: Vec<SocketAddr> = (1..=4)
+            .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+            .collect();
+        let mut manager = GameStateManager::new();
+        let (state, _, _) = match_between(addrs[0], addrs[1]).await;
+        let first = manager.host(state);
+        let (state, session, _) = match_between(addrs[2], addrs[3]).await;
+        let second = manager.host(state);
+
+        assert_ne!(first, second);
+        assert!(matches!(
+            manager.find_by_session(session, addrs[2]),
+            Ok((id, PlayerId::PLAYER_1)) if id == second
+        ));
+        // A session only counts from the address it was handed to
+        assert!(matches!(
+            manager.find_by_session(session, addrs[0]),
+            Err(ServerError::InvalidSession)
+        ));
+        assert_eq!(
+            manager.find_by_addr(addrs[1]),
+            Some((first, PlayerId::PLAYER_2))
+        );
+
+        assert_eq!(
+            manager
+                .get_metadata(second)
+                .ok()
+                .map(|metadata| metadata.seed),
+            manager.get_match(second).ok().map(|state| state.get_seed())
+        );
+
+        manager.end_match(first);
+        assert_eq!(manager.find_by_addr(addrs[1]), None);
+        assert_eq!(manager.get_match_ids(), vec![second]);
+    }
+
+    #[tokio::test]
+    async fn test_ended_match_is_not_found() {
+        let addrs: Vec<SocketAddr> = (1..=2)
+            .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+            .collect();
+        let mut manager = GameStateManager::new();
+        let (state, session, _) = match_between(addrs[0], addrs[1]).await;
+        let id = manager.host(state);
+        manager.end_match(id);
+
+        assert!(matches!(
+            manager.get_match(id),
+            Err(ServerError::MatchNotFound)
+        ));
+        assert!(matches!(
+            manager.get_metadata(id),
+            Err(ServerError::MatchNotFound)
+        ));
+        assert!(matches!(
+            manager.find_by_session(session, addrs[0]),
+            Err(ServerError::MatchNotFound)
+        ));
+        // Never handed out at all
+        assert!(matches!(
+            manager.find_by_session(SessionToken::new(7), addrs[0]),
+            Err(ServerError::InvalidSession)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ending_an_old_match_keeps_the_new_seat() {
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+            .collect();
+        let mut manager = GameStateManager::new();
+        let (state, _, _) = match_between(addrs[0], addrs[1]).await;
+        let old = manager.host(state);
+        // The first player queued again while the old match was winding down
+        let (state, session, _) = match_between(addrs[0], addrs[2]).await;
+        let new = manager.host(state);
+
+        manager.end_match(old);
+        assert_eq!(
+            manager.find_by_addr(addrs[0]),
+            Some((new, PlayerId::PLAYER_1))
+        );
+        assert!(matches!(
+            manager.find_by_session(session, addrs[0]),
+            Ok((id, PlayerId::PLAYER_1)) if id == new
+        ));
+        assert_eq!(manager.find_by_addr(addrs[1]), None);
+    }
 }
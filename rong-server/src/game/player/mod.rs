@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod player;
 pub mod player_manager;
 
@@ -1,13 +1,19 @@
-use rong_shared::{error, model};
+use crate::game::state::TICKS_PER_SECOND;
+use rong_shared::model;
+use rong_shared::transport::sequence_greater_than;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
 
 const PLAYER_WIDTH: f32 = 0.125; // 12.5% of screen width
 const PLAYER_HEIGHT: f32 = 0.0167; // 1.67% of screen height
-const MAX_SPEED: f32 = 0.2;
-const ACCELERATION: f32 = 0.02;
-const DECELERATION: f32 = 0.25;
+
+// Speeds are per tick, written as per second over the tick rate
+const MAX_SPEED: f32 = 0.2 / TICKS_PER_SECOND as f32;
+const ACCELERATION: f32 = 0.02 / TICKS_PER_SECOND as f32; // Per movement input, one per tick
+const DECELERATION: f32 = 0.25 / (TICKS_PER_SECOND * TICKS_PER_SECOND) as f32; // Lost every tick
+
+// Inputs queued any deeper would be applied too late to matter, the oldest give way
+const MAX_QUEUED_INPUTS: usize = 8;
 
 #[derive(Clone)]
 pub struct Player {
@@ -15,6 +21,12 @@ pub struct Player {
     position: model::Position,
     velocity: f32,
     addr: SocketAddr,
+    // Newest MovementInput received, see queue_input
+    last_received: Option<u32>,
+    // Received inputs waiting for their tick, oldest first
+    inputs: VecDeque<(u32, model::Movement)>,
+    // Newest MovementInput applied, what GameUpdateData acknowledges
+    last_input: Option<u32>,
 }
 
 impl Player {
@@ -24,6 +36,9 @@ impl Player {
             position: (0.5, 0.5), // Start at center
             velocity: 0.0,
             addr,
+            last_received: None,
+            inputs: VecDeque::new(),
+            last_input: None,
         }
     }
 
@@ -31,14 +46,24 @@ impl Player {
         self.addr
     }
 
-    pub fn update_position(&mut self, dt: f32) {
+    // Moves the paddle by one tick, after applying the next queued input
+    pub fn update_position(&mut self) {
+        if let Some((input_id, movement)) = self.inputs.pop_front() {
+            match movement {
+                model::Movement::Up => self.move_up(),
+                model::Movement::Down => self.move_down(),
+                model::Movement::Stop => self.stop(),
+            }
+            self.last_input = Some(input_id);
+        }
+
         let (_, y) = self.position;
-        let new_y = y + self.velocity * dt;
+        let new_y = y + self.velocity;
         self.position.1 = new_y.clamp(PLAYER_HEIGHT / 2.0, 1.0 - PLAYER_HEIGHT / 2.0);
 
         // Decelerate
         if self.velocity.abs() > 0.0 {
-            let deceleration = DECELERATION * dt * self.velocity.signum();
+            let deceleration = DECELERATION * self.velocity.signum();
             if self.velocity.abs() > deceleration.abs() {
                 self.velocity -= deceleration;
             } else {
@@ -71,6 +96,27 @@ impl Player {
     pub fn stop(&mut self) {
         self.velocity = 0.0;
     }
+
+    // Holds the input for the next tick without a queued one. False if a newer
+    // input was already received, in which case this one is dropped
+    pub fn queue_input(&mut self, input_id: u32, movement: model::Movement) -> bool {
+        if let Some(last_received) = self.last_received {
+            if !sequence_greater_than(input_id, last_received) {
+                return false;
+            }
+        }
+        self.last_received = Some(input_id);
+
+        if self.inputs.len() == MAX_QUEUED_INPUTS {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back((input_id, movement));
+        true
+    }
+
+    pub fn get_last_input(&self) -> Option<u32> {
+        self.last_input
+    }
 }
 
 #[cfg(test)]
@@ -82,10 +128,10 @@ mod tests {
     #[test]
     fn test_player_movement() {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut player = Player::new(PlayerId::Player1, addr);
+        let mut player = Player::new(PlayerId::PLAYER_1, addr);
         let initial_position = player.get_position();
         player.move_up();
-        player.update_position(0.1);
+        player.update_position();
         let new_position = player.get_position();
         assert!(
             new_position.1 > initial_position.1,
@@ -96,7 +142,7 @@ mod tests {
     #[test]
     fn test_player_bounds() {
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut player = Player::new(PlayerId::Player1, addr);
+        let mut player = Player::new(PlayerId::PLAYER_1, addr);
 
         // Try to move player out of bounds
         player.set_position(0.0, -0.1);
@@ -111,4 +157,42 @@ mod tests {
             "Player should not move above the top of the screen"
         );
     }
+
+    #[test]
+    fn test_stale_input_is_dropped() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut player = Player::new(PlayerId::PLAYER_1, addr);
+
+        assert!(player.queue_input(5, model::Movement::Up));
+        assert!(
+            !player.queue_input(4, model::Movement::Up),
+            "Older input should be dropped"
+        );
+        assert!(
+            !player.queue_input(5, model::Movement::Up),
+            "Duplicate input should be dropped"
+        );
+        player.update_position();
+        assert_eq!(player.get_last_input(), Some(5));
+    }
+
+    #[test]
+    fn test_one_input_per_tick() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut player = Player::new(PlayerId::PLAYER_1, addr);
+
+        // Both arrived in one burst, they still take a tick each
+        player.queue_input(1, model::Movement::Up);
+        player.queue_input(2, model::Movement::Up);
+        assert_eq!(
+            player.get_last_input(),
+            None,
+            "Nothing applied before a tick"
+        );
+
+        player.update_position();
+        assert_eq!(player.get_last_input(), Some(1));
+        player.update_position();
+        assert_eq!(player.get_last_input(), Some(2));
+    }
 }
@@ -2,37 +2,44 @@ use super::Player;
 use rong_shared::{error, model};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::UdpSocket;
 
 #[derive(Clone)]
 pub struct PlayerConnection {
     player_id: model::PlayerId,
-    addr: SocketAddr,
+    session: model::SessionToken,
     last_seen: Instant,
 }
 
-#[derive(Clone)]
+// Players only ever hear from the server's own transport, see ClientHandler
+#[derive(Clone, Default)]
 pub struct PlayerManager {
     players: HashMap<model::PlayerId, Player>,
     connections: HashMap<SocketAddr, PlayerConnection>,
-    socket: Arc<UdpSocket>,
 }
 
 impl PlayerManager {
-    pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self {
-            players: HashMap::new(),
-            connections: HashMap::new(),
-            socket,
-        }
+    pub fn new() -> Self {
+        Self::default()
     }
 
     pub async fn add_player(
         &mut self,
         id: model::PlayerId,
         addr: SocketAddr,
+    ) -> Result<model::SessionToken, error::ServerError> {
+        let session = model::SessionToken::new(rand::random());
+        self.add_player_with_session(id, addr, session).await?;
+        Ok(session)
+    }
+
+    // For players who were handed their session before being seated, e.g.
+    // when they joined the matchmaking queue
+    pub async fn add_player_with_session(
+        &mut self,
+        id: model::PlayerId,
+        addr: SocketAddr,
+        session: model::SessionToken,
     ) -> Result<(), error::ServerError> {
         let player = Player::new(id, addr);
         self.players.insert(id, player);
@@ -40,13 +47,25 @@ impl PlayerManager {
             addr,
             PlayerConnection {
                 player_id: id,
-                addr,
+                session,
                 last_seen: Instant::now(),
             },
         );
         Ok(())
     }
 
+    // A packet only speaks for a player when both its token and source address match
+    pub fn resolve_session(
+        &self,
+        session: model::SessionToken,
+        addr: SocketAddr,
+    ) -> Option<model::PlayerId> {
+        self.connections
+            .get(&addr)
+            .filter(|conn| conn.session == session)
+            .map(|conn| conn.player_id)
+    }
+
     pub async fn remove_player(&mut self, id: model::PlayerId) -> Result<(), error::ServerError> {
         self.players.remove(&id);
         self.connections.retain(|_, conn| conn.player_id != id);
@@ -56,10 +75,9 @@ impl PlayerManager {
     pub async fn update_player_position(
         &mut self,
         id: model::PlayerId,
-        dt: f32,
     ) -> Result<(), error::ServerError> {
         if let Some(player) = self.players.get_mut(&id) {
-            player.update_position(dt);
+            player.update_position();
         }
         Ok(())
     }
@@ -71,23 +89,6 @@ impl PlayerManager {
             .collect()
     }
 
-    pub async fn send_to_player(
-        &self,
-        id: model::PlayerId,
-        msg: &[u8],
-    ) -> Result<usize, error::ServerError> {
-        if let Some(addr) = self
-            .connections
-            .values()
-            .find(|conn| conn.player_id == id)
-            .map(|conn| conn.addr)
-        {
-            Ok(self.socket.send_to(msg, addr).await?)
-        } else {
-            Err(error::ServerError::PlayerNotFound)
-        }
-    }
-
     pub fn get_player(&self, id: model::PlayerId) -> Option<&Player> {
         self.players.get(&id)
     }
@@ -114,15 +115,32 @@ impl PlayerManager {
         self.players.len()
     }
 
-    pub fn remove_inactive_players(&mut self, timeout: Duration) {
+    // Returns the players that were dropped, so the game can react to them leaving
+    pub fn remove_inactive_players(&mut self, timeout: Duration) -> Vec<model::PlayerId> {
         let now = Instant::now();
+        let mut removed = Vec::new();
         self.connections.retain(|_, conn| {
             if now.duration_since(conn.last_seen) > timeout {
                 self.players.remove(&conn.player_id);
+                removed.push(conn.player_id);
                 false
             } else {
                 true
             }
         });
+        removed.sort();
+        removed
+    }
+
+    // Every seated player's address and the session it plays under
+    pub fn get_sessions(&self) -> Vec<(SocketAddr, model::SessionToken)> {
+        self.connections
+            .iter()
+            .map(|(&addr, conn)| (addr, conn.session))
+            .collect()
+    }
+
+    pub fn get_player_id(&self, addr: SocketAddr) -> Option<model::PlayerId> {
+        self.connections.get(&addr).map(|conn| conn.player_id)
     }
 }
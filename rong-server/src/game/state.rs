@@ -1,41 +1,83 @@
 use super::ball::Ball;
 use super::player::player_manager::PlayerManager;
 use super::player::Player;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rong_shared::error::{GameError, Result};
-use rong_shared::model::{GameState, PlayerId, PositionPacket, ScorePacket};
+#[cfg(feature = "quantized-positions")]
+use rong_shared::model::quantize_position;
+use rong_shared::model::{
+    DisconnectReason, Entity, EntityId, GameEvent, GameStatus, GameUpdateData, PlayerId,
+    PositionData, Score, ScoreData, SessionToken, Wall,
+};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+const POINTS_TO_WIN: u8 = 11;
+
+// The simulation always moves in steps of exactly one tick, however often
+// it's driven, so the same inputs make the same match
+pub const TICKS_PER_SECOND: u32 = 60;
+pub const TICK: Duration = Duration::from_nanos(1_000_000_000 / TICKS_PER_SECOND as u64);
+// After a stall the game catches up this many ticks at most, rather than
+// spending ever longer catching up
+const MAX_CATCH_UP_TICKS: u32 = 15;
+
 pub struct State {
     pub players: PlayerManager,
     pub ball: Ball,
-    state: GameState,
-    scores: ScorePacket,
+    state: GameStatus,
+    scores: ScoreData,
     last_update: Instant,
+    // Time not yet simulated, always under a tick after advance
+    accumulator: Duration,
+    ticks: u64,
     game_duration: Duration,
+    // Gameplay events since the last drain, in the order they happened
+    events: Vec<GameEvent>,
+    // Every random draw in the match comes from here, so the seed replays it
+    rng: StdRng,
+    seed: u64,
 }
 
 impl State {
     pub fn new(players: PlayerManager) -> Self {
+        State::with_seed(players, rand::random())
+    }
+
+    // Same seed and same inputs, same match
+    pub fn with_seed(players: PlayerManager, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
         State {
             players,
-            ball: Ball::new(),
-            state: GameState::WaitingForPlayers,
-            scores: ScorePacket::new(0, 0),
+            ball: Ball::new(&mut rng),
+            state: GameStatus::WaitingForPlayers,
+            scores: ScoreData::new(Score::default(), Score::default()),
             last_update: Instant::now(),
+            accumulator: Duration::ZERO,
+            ticks: 0,
             game_duration: Duration::from_secs(0),
+            events: Vec::new(),
+            rng,
+            seed,
         }
     }
 
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
     pub fn start_new_match(&mut self) -> Result<()> {
         match self.state {
-            GameState::WaitingForPlayers => {
+            GameStatus::WaitingForPlayers => {
                 if self.players.get_player_count() == 2 {
-                    self.state = GameState::GameStarted;
+                    self.state = GameStatus::GameStarted;
                     self.game_duration = Duration::from_secs(0);
-                    self.ball.reset(rand::random::<u8>() % 2 + 1);
-                    self.scores = ScorePacket::new(0, 0);
+                    let first = self.random_player();
+                    self.serve(first);
+                    self.scores = ScoreData::new(Score::default(), Score::default());
                     self.last_update = Instant::now();
+                    self.accumulator = Duration::ZERO;
 
                     // Reset player positions
                     for player in self.players.get_players_mut().values_mut() {
@@ -48,34 +90,55 @@ impl State {
                     );
                     Ok(())
                 } else {
-                    Err(GameError::Io(
-                        "Not enough players to start a match".to_string(),
-                    ))
+                    Err(GameError::NotEnoughPlayers {
+                        required: 2,
+                        present: self.players.get_player_count(),
+                    })
                 }
             }
-            _ => Err(GameError::Io(
-                "Cannot start a new match in the current state".to_string(),
-            )),
+            _ => Err(GameError::InvalidState(self.state)),
         }
     }
 
+    // Runs however many whole ticks have passed since the last call
     pub async fn update(&mut self) -> Result<()> {
         let now = Instant::now();
-        let dt = now.duration_since(self.last_update).as_secs_f32();
+        let elapsed = now.duration_since(self.last_update);
         self.last_update = now;
+        self.advance(elapsed).await?;
+        Ok(())
+    }
+
+    // Adds elapsed to the time waiting to be simulated and runs it in whole
+    // ticks, keeping the remainder for next time. Returns the ticks run
+    pub async fn advance(&mut self, elapsed: Duration) -> Result<u32> {
+        self.accumulator = (self.accumulator + elapsed).min(TICK * MAX_CATCH_UP_TICKS);
+        let mut ran = 0;
+        while self.accumulator >= TICK {
+            self.accumulator -= TICK;
+            self.tick().await?;
+            ran += 1;
+        }
+        Ok(ran)
+    }
 
+    // One step of the simulation
+    pub async fn tick(&mut self) -> Result<()> {
+        self.ticks += 1;
         match self.state {
-            GameState::GameStarted => {
-                self.game_duration += now.duration_since(self.last_update);
-                self.update_player_positions(dt).await?;
+            GameStatus::GameStarted => {
+                self.game_duration += TICK;
+                self.update_player_positions().await?;
                 self.update_ball_position();
                 self.handle_collisions();
                 self.check_scoring();
+                #[cfg(feature = "quantized-positions")]
+                self.snap_to_wire_grid();
             }
-            GameState::GameOver => {
+            GameStatus::GameOver => {
                 // Do nothing
             }
-            GameState::WaitingForPlayers => {
+            GameStatus::WaitingForPlayers => {
                 // Check if we can start the game
                 if self.players.get_player_count() == 2 {
                     self.start_new_match()?;
@@ -85,100 +148,189 @@ impl State {
         Ok(())
     }
 
-    async fn update_player_positions(&mut self, dt: f32) -> Result<()> {
-        for player_id in [PlayerId::Player1, PlayerId::Player2].iter() {
-            self.players
-                .update_player_position(*player_id, dt)
-                .await
-                .map_err(|e| GameError::Io(e.to_string()))?;
+    // Ticks simulated since the State was made
+    pub fn get_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    async fn update_player_positions(&mut self) -> Result<()> {
+        let player_ids: Vec<PlayerId> = self.players.get_players().keys().copied().collect();
+        for player_id in player_ids {
+            self.players.update_player_position(player_id).await?;
         }
         Ok(())
     }
 
     fn update_ball_position(&mut self) {
         let players: Vec<Player> = self.players.get_players().values().cloned().collect();
-        self.ball.update_position(&players);
+        if let Some(player_id) = self.ball.update_position(&players) {
+            self.events.push(GameEvent::PaddleHit(player_id));
+        }
     }
 
     fn handle_collisions(&mut self) {
         if self.ball.collides_with_wall() {
             self.ball.bounce_off_wall();
+            // Left and right walls score instead, see check_scoring
+            match self.ball.which_wall() {
+                "top" => self.events.push(GameEvent::WallBounce(Wall::Top)),
+                "bottom" => self.events.push(GameEvent::WallBounce(Wall::Bottom)),
+                _ => {}
+            }
         }
     }
 
     fn check_scoring(&mut self) {
         if self.ball.collides_with_wall() {
-            let (player1_score, player2_score) = self.scores.get_payload();
-            match self.ball.which_wall() {
+            // The player the ball is served to next
+            let conceded = match self.ball.which_wall() {
                 "left" => {
-                    self.scores = ScorePacket::new(player1_score, player2_score + 1);
-                    self.ball.reset(1);
+                    self.award_point(PlayerId::PLAYER_2);
+                    PlayerId::PLAYER_1
                 }
                 "right" => {
-                    self.scores = ScorePacket::new(player1_score + 1, player2_score);
-                    self.ball.reset(2);
+                    self.award_point(PlayerId::PLAYER_1);
+                    PlayerId::PLAYER_2
                 }
-                _ => {} // Top and bottom walls don't affect score
+                _ => return, // Top and bottom walls don't affect score
+            };
+            // No serve after the winning point
+            if self.state == GameStatus::GameStarted {
+                self.serve(conceded);
             }
         }
     }
 
-    pub async fn get_positions(&self) -> Result<PositionPacket> {
-        if self.state != GameState::GameStarted {
-            return Err(GameError::Io("Game has not started yet".to_string()));
+    fn award_point(&mut self, scorer: PlayerId) {
+        self.update_score(scorer);
+
+        self.events.push(GameEvent::PointScored(scorer));
+        let points = self.scores[scorer].get_points();
+        if points + 1 == POINTS_TO_WIN {
+            self.events.push(GameEvent::MatchPoint(scorer));
+        } else if points >= POINTS_TO_WIN {
+            self.end_game();
         }
+    }
 
-        let player_positions = self.players.get_positions().await;
-        let ball_position = self.ball.get_position();
+    // Put the ball back in the middle, heading towards the given player
+    fn serve(&mut self, to: PlayerId) {
+        // The ball only knows the classic layout, serving down or up the field
+        let serve_to_player = if to == PlayerId::PLAYER_1 { 1 } else { 2 };
+        self.ball.reset(serve_to_player, &mut self.rng);
+        self.events.push(GameEvent::ServeStarted(to));
+    }
 
-        if player_positions.len() != 2 {
-            return Err(GameError::Io("Not enough players".to_string()));
+    fn random_player(&mut self) -> PlayerId {
+        if self.rng.gen() {
+            PlayerId::PLAYER_1
+        } else {
+            PlayerId::PLAYER_2
+        }
+    }
+
+    // Events since the last call, for the network side to broadcast
+    pub fn drain_events(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Keep the simulation on the grid clients receive, so both sides agree exactly
+    #[cfg(feature = "quantized-positions")]
+    fn snap_to_wire_grid(&mut self) {
+        for player in self.players.get_players_mut().values_mut() {
+            let (x, y) = quantize_position(player.get_position());
+            player.set_position(x, y);
         }
+        let (x, y) = quantize_position(self.ball.get_position());
+        self.ball.set_position(x, y);
+    }
 
-        let position_packet =
-            PositionPacket::new(player_positions[0].1, player_positions[1].1, ball_position);
+    // Every paddle and the ball, as clients receive them
+    pub async fn get_positions(&self) -> PositionData {
+        let mut player_positions = self.players.get_positions().await;
+        // Players live in a map, keep the order stable so deltas only see real moves
+        player_positions.sort_by_key(|(player_id, _)| *player_id);
+
+        let mut entities: Vec<Entity> = player_positions
+            .into_iter()
+            .map(|(player_id, position)| Entity::new(EntityId::Player(player_id), position))
+            .collect();
+        entities.push(Entity::new(EntityId::Ball, self.ball.get_position()));
+        PositionData::from_entities(entities)
+    }
 
-        Ok(position_packet)
+    // What a GameUpdate carries right now
+    pub async fn get_game_update(&self) -> GameUpdateData {
+        GameUpdateData::new(self.get_positions().await, self.scores.clone(), self.state)
+            .with_last_inputs(self.get_last_inputs())
     }
 
-    pub fn get_state(&self) -> GameState {
+    pub fn get_state(&self) -> GameStatus {
         self.state
     }
 
-    pub fn get_scores(&self) -> &ScorePacket {
+    pub fn get_scores(&self) -> &ScoreData {
         &self.scores
     }
 
     pub fn update_score(&mut self, player_id: PlayerId) {
-        match player_id {
-            PlayerId::Player1 => {
-                let (player1_score, player2_score) = self.scores.get_payload();
-                self.scores = ScorePacket::new(player1_score + 1, player2_score);
-            }
-            PlayerId::Player2 => {
-                let (player1_score, player2_score) = self.scores.get_payload();
-                self.scores = ScorePacket::new(player1_score, player2_score + 1);
-            }
-        }
+        let points = self.scores[player_id].get_points();
+        self.scores[player_id] = Score::new(points + 1);
     }
 
     pub fn start_game(&mut self) -> Result<()> {
-        if self.state == GameState::WaitingForPlayers {
-            self.state = GameState::GameStarted;
+        if self.state == GameStatus::WaitingForPlayers {
+            self.state = GameStatus::GameStarted;
             self.game_duration = Duration::from_secs(0);
-            self.ball.reset(rand::random::<u8>() % 2 + 1);
+            let first = self.random_player();
+            self.serve(first);
             println!(
                 "Game started with {} players",
                 self.players.get_player_count()
             );
             Ok(())
         } else {
-            Err(GameError::Io("Game already started".to_string()))
+            Err(GameError::InvalidState(self.state))
         }
     }
 
     pub fn end_game(&mut self) {
-        self.state = GameState::GameOver;
+        self.state = GameStatus::GameOver;
+    }
+
+    // A player left on purpose or went quiet. Whoever is left gets told,
+    // and a match in progress can't go on without them
+    pub async fn disconnect_player(
+        &mut self,
+        player_id: PlayerId,
+        reason: DisconnectReason,
+    ) -> Result<()> {
+        if self.players.get_player(player_id).is_none() {
+            return Ok(());
+        }
+        self.players.remove_player(player_id).await?;
+        self.player_left(player_id, reason);
+        Ok(())
+    }
+
+    // Same as disconnect_player for everyone silent longer than timeout
+    pub fn remove_inactive_players(&mut self, timeout: Duration) -> Vec<PlayerId> {
+        let removed = self.players.remove_inactive_players(timeout);
+        for &player_id in &removed {
+            self.player_left(player_id, DisconnectReason::TimedOut);
+        }
+        removed
+    }
+
+    fn player_left(&mut self, player_id: PlayerId, reason: DisconnectReason) {
+        println!("Player {:?} left: {:?}", player_id, reason);
+        self.events.push(GameEvent::PlayerLeft {
+            player: player_id,
+            reason,
+        });
+        if self.state == GameStatus::GameStarted {
+            self.end_game();
+        }
     }
 
     pub fn get_player_count(&self) -> usize {
@@ -196,26 +348,150 @@ impl State {
     }
 
     pub fn reset(&mut self) {
-        self.scores = ScorePacket::new(0, 0);
-        self.ball.reset(rand::random::<u8>() % 2 + 1);
-        self.state = GameState::WaitingForPlayers;
+        self.scores = ScoreData::new(Score::default(), Score::default());
+        let serve_to_player = self.rng.gen_range(1..=2);
+        self.ball.reset(serve_to_player, &mut self.rng);
+        self.events.clear();
+        self.state = GameStatus::WaitingForPlayers;
         self.game_duration = Duration::from_secs(0);
+        self.accumulator = Duration::ZERO;
         // Reset player positions
         for player in self.players.get_players_mut().values_mut() {
             player.set_position(0.5, 0.5); // Set to center of the screen
         }
     }
 
-    pub async fn add_player(&mut self, id: PlayerId, addr: SocketAddr) -> Result<()> {
-        self.players
-            .add_player(id, addr)
-            .await
-            .map_err(|e| GameError::Io(e.to_string()))?;
+    // Queues a client's numbered input for the coming ticks, unless a newer one
+    // from them already arrived. Inputs only take effect on a tick, so when a
+    // packet lands never changes what it does
+    pub fn queue_input(
+        &mut self,
+        player_id: PlayerId,
+        input_id: u32,
+        movement: rong_shared::model::Movement,
+    ) {
+        if let Some(player) = self.players.get_player_mut(player_id) {
+            player.queue_input(input_id, movement);
+        }
+    }
+
+    // What each player's GameUpdateData should acknowledge
+    pub fn get_last_inputs(&self) -> Vec<(PlayerId, u32)> {
+        let mut last_inputs: Vec<(PlayerId, u32)> = self
+            .players
+            .get_players()
+            .values()
+            .filter_map(|player| Some((player.get_id(), player.get_last_input()?)))
+            .collect();
+        last_inputs.sort();
+        last_inputs
+    }
+
+    pub async fn add_player(&mut self, id: PlayerId, addr: SocketAddr) -> Result<SessionToken> {
+        let session = self.players.add_player(id, addr).await?;
         println!(
             "Player {:?} added. Total players: {}",
             id,
             self.players.get_player_count()
         );
-        Ok(())
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn started_match() -> State {
+        started_match_with_seed(rand::random()).await
+    }
+
+    async fn started_match_with_seed(seed: u64) -> State {
+        let mut state = State::with_seed(PlayerManager::new(), seed);
+        state
+            .add_player(PlayerId::PLAYER_1, "127.0.0.1:1".parse().unwrap())
+            .await
+            .unwrap();
+        state
+            .add_player(PlayerId::PLAYER_2, "127.0.0.1:2".parse().unwrap())
+            .await
+            .unwrap();
+        state.start_new_match().unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn test_advance_runs_whole_ticks() {
+        let mut state = started_match().await;
+
+        assert_eq!(state.advance(TICK * 5 / 2).await.unwrap(), 2);
+        // The leftover half tick counts towards the next one
+        assert_eq!(state.advance(TICK / 2).await.unwrap(), 1);
+        assert_eq!(state.get_ticks(), 3);
+
+        // A long stall only catches up so far
+        let ran = state.advance(Duration::from_secs(10)).await.unwrap();
+        assert_eq!(ran, MAX_CATCH_UP_TICKS);
+    }
+
+    #[tokio::test]
+    async fn test_speed_doesnt_depend_on_update_rate() {
+        let mut coarse = started_match().await;
+        let mut fine = started_match().await;
+        for state in [&mut coarse, &mut fine] {
+            state.ball.set_position(0.5, 0.5);
+            state.move_player(PlayerId::PLAYER_1, rong_shared::model::Movement::Up);
+        }
+
+        coarse.advance(TICK * 10).await.unwrap();
+        for _ in 0..20 {
+            fine.advance(TICK / 2).await.unwrap();
+        }
+
+        let paddle = |state: &State| {
+            state
+                .players
+                .get_player(PlayerId::PLAYER_1)
+                .unwrap()
+                .get_position()
+        };
+        assert_eq!(coarse.get_ticks(), fine.get_ticks());
+        assert_eq!(paddle(&coarse), paddle(&fine));
+    }
+
+    #[tokio::test]
+    async fn test_inputs_wait_for_a_tick() {
+        let mut state = started_match().await;
+        let paddle = |state: &State| {
+            state
+                .players
+                .get_player(PlayerId::PLAYER_1)
+                .unwrap()
+                .get_position()
+        };
+        let start = paddle(&state);
+
+        state.queue_input(PlayerId::PLAYER_1, 1, rong_shared::model::Movement::Up);
+        assert_eq!(paddle(&state), start, "Inputs only move paddles on a tick");
+        assert!(state.get_last_inputs().is_empty());
+
+        state.advance(TICK).await.unwrap();
+        assert!(paddle(&state).1 > start.1);
+        assert_eq!(state.get_last_inputs(), vec![(PlayerId::PLAYER_1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_same_serves() {
+        let mut first = started_match_with_seed(7).await;
+        let mut second = started_match_with_seed(7).await;
+        assert_eq!(first.get_seed(), 7);
+
+        for state in [&mut first, &mut second] {
+            state.advance(TICK * 10).await.unwrap();
+            state.reset();
+            state.start_new_match().unwrap();
+            state.advance(TICK * 10).await.unwrap();
+        }
+        assert_eq!(first.ball.get_position(), second.ball.get_position());
     }
 }
@@ -1,18 +1,293 @@
+use rong_shared::model::{DisconnectReason, GameStatus, PlayerId, ServerMessage};
+use rong_shared::transport::{NetworkConditions, SimulatedTransport, Transport};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, MissedTickBehavior};
 
-use crate::game::GameStateManager;
+use crate::game::state::{State, TICK};
+use crate::game::{GameStateManager, MatchId};
 use crate::matchmaking::MatchmakingManager;
 use crate::network::NetworkManager;
 
-const TICK_RATE: Duration = Duration::from_millis(16);
-const BROADCAST_INTERVAL: Duration = Duration::from_millis(50);
+pub const SERVER_ADDR: &str = "0.0.0.0:2906";
 
-pub struct GameServer {
-    network_manager: Arc<Mutex<NetworkManager>>,
+// Ticks are fixed length, a late timer just means the next update runs two
+const TICK_RATE: Duration = TICK;
+// How often clients are checked for a GameUpdate. Each one is actually sent
+// at its own SendRate, between SendRateConfig's bounds
+const BROADCAST_INTERVAL: Duration = TICK_RATE;
+// Reliable resends, keepalives and timeouts are checked this often
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(50);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const MATCHMAKING_MAX_WAIT: Duration = Duration::from_secs(30);
+// A finished match stays up this long so its players get updates showing
+// it over, even at the slowest SendRate
+const MATCH_TEARDOWN_DELAY: Duration = Duration::from_secs(1);
+
+/*  Stops a running GameServer from another task, e.g. on ctrl-c */
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+/*
+    Runs the server on one task: packets from clients, the game tick,
+    GameUpdate broadcasts and connection upkeep all take turns in a single
+    select! loop, so none of them ever waits on a lock held by another.
+*/
+pub struct GameServer<T: Transport = UdpSocket> {
+    network_manager: NetworkManager<T>,
     matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
     game_state_manager: Arc<Mutex<GameStateManager>>,
+    shutdown: ShutdownHandle,
+    shutdown_signal: watch::Receiver<bool>,
+    // When each finished match was first seen over, see MATCH_TEARDOWN_DELAY
+    finished: HashMap<MatchId, Instant>,
+}
+
+impl GameServer<SimulatedTransport<UdpSocket>> {
+    // Conditions are perfect unless the command line asked for a bad network
+    pub async fn new(
+        server_addr: SocketAddr,
+        conditions: NetworkConditions,
+    ) -> Result<Self, std::io::Error> {
+        let (game_state_manager, matchmaking_manager) = managers();
+        let network_manager = NetworkManager::with_conditions(
+            server_addr,
+            conditions,
+            game_state_manager.clone(),
+            matchmaking_manager.clone(),
+        )
+        .await?;
+        Ok(GameServer::from_parts(
+            network_manager,
+            game_state_manager,
+            matchmaking_manager,
+        ))
+    }
+}
+
+impl<T: Transport> GameServer<T> {
+    pub fn with_transport(transport: T) -> Self {
+        let (game_state_manager, matchmaking_manager) = managers();
+        let network_manager = NetworkManager::with_transport(
+            transport,
+            game_state_manager.clone(),
+            matchmaking_manager.clone(),
+        );
+        GameServer::from_parts(network_manager, game_state_manager, matchmaking_manager)
+    }
+
+    fn from_parts(
+        network_manager: NetworkManager<T>,
+        game_state_manager: Arc<Mutex<GameStateManager>>,
+        matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
+    ) -> Self {
+        let (sender, shutdown_signal) = watch::channel(false);
+        GameServer {
+            network_manager,
+            matchmaking_manager,
+            game_state_manager,
+            shutdown: ShutdownHandle {
+                sender: Arc::new(sender),
+            },
+            shutdown_signal,
+            finished: HashMap::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.network_manager.get_client_handler().local_addr()
+    }
+
+    pub fn get_shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub async fn listen_websocket(&mut self, addr: SocketAddr) -> Result<(), std::io::Error> {
+        self.network_manager
+            .get_client_handler_mut()
+            .listen_websocket(addr)
+            .await
+    }
+
+    // Serves until shut down, then tells every client the server is going away
+    pub async fn run(mut self) -> Result<(), std::io::Error> {
+        let mut ticks = time::interval(TICK_RATE);
+        let mut broadcasts = time::interval(BROADCAST_INTERVAL);
+        let mut housekeeping = time::interval(HOUSEKEEPING_INTERVAL);
+        let mut pings = time::interval(PING_INTERVAL);
+        // A late tick shouldn't be followed by a burst of catch-up ones
+        for timer in [&mut ticks, &mut broadcasts, &mut housekeeping, &mut pings] {
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        }
+
+        while !*self.shutdown_signal.borrow() {
+            tokio::select! {
+                // The sender lives in self, so this only returns on shutdown
+                _ = self.shutdown_signal.changed() => {}
+                received = self.network_manager.get_client_handler().next_datagram() => {
+                    match received {
+                        Ok((datagram, addr)) => {
+                            self.network_manager.handle_datagram(&datagram, addr).await
+                        }
+                        // e.g. a port unreachable for a client that just left
+                        Err(e) => eprintln!("Failed to receive from socket: {}", e),
+                    }
+                }
+                _ = ticks.tick() => self.tick().await,
+                _ = broadcasts.tick() => self.broadcast().await,
+                _ = housekeeping.tick() => self.housekeeping().await,
+                _ = pings.tick() => {
+                    if let Err(e) = self.network_manager.get_client_handler_mut().ping_clients().await {
+                        eprintln!("Failed to ping clients: {}", e);
+                    }
+                }
+            }
+        }
+
+        println!("Shutting down");
+        self.network_manager
+            .get_client_handler_mut()
+            .disconnect_all(DisconnectReason::ServerShutdown)
+            .await;
+        Ok(())
+    }
+
+    // Hosts whatever matchmaking paired up, advances every match, then tells
+    // each match's players what happened in it
+    async fn tick(&mut self) {
+        let made = match self.matchmaking_manager.lock().await.update().await {
+            Ok(made) => made,
+            Err(e) => {
+                eprintln!("Matchmaking failed: {}", e);
+                Vec::new()
+            }
+        };
+
+        let mut outgoing: Vec<(SocketAddr, ServerMessage)> = Vec::new();
+        let mut matches = self.game_state_manager.lock().await;
+        for state in made {
+            let players = get_players(&state);
+            let seed = state.get_seed();
+            let match_id = matches.host(state);
+            println!("Hosting match {:?} with seed {}", match_id, seed);
+            for (player_id, addr) in players {
+                outgoing.push((addr, ServerMessage::GameFound(player_id)));
+            }
+        }
+
+        let now = Instant::now();
+        for match_id in matches.get_match_ids() {
+            let Ok(state) = matches.get_match_mut(match_id) else {
+                continue;
+            };
+            if let Err(e) = state.update().await {
+                eprintln!("Failed to update match {:?}: {}", match_id, e);
+            }
+            let players = get_players(state);
+            for event in state.drain_events() {
+                for &(_, addr) in &players {
+                    outgoing.push((addr, ServerMessage::GameEvent(event.clone())));
+                }
+            }
+
+            let torn_down = if players.is_empty() {
+                // Nobody left to tell, no reason to wait
+                true
+            } else if state.get_state() == GameStatus::GameOver {
+                let since = *self.finished.entry(match_id).or_insert(now);
+                now.duration_since(since) >= MATCH_TEARDOWN_DELAY
+            } else {
+                false
+            };
+            if torn_down {
+                matches.end_match(match_id);
+                self.finished.remove(&match_id);
+                println!("Match {:?} torn down", match_id);
+            }
+        }
+        drop(matches);
+
+        let client_handler = self.network_manager.get_client_handler_mut();
+        for (addr, message) in outgoing {
+            if let Err(e) = client_handler.send_to(&message, addr).await {
+                eprintln!("Failed to send to {}: {}", addr, e);
+            }
+        }
+    }
+
+    async fn broadcast(&mut self) {
+        let mut updates = Vec::new();
+        {
+            let matches = self.game_state_manager.lock().await;
+            for match_id in matches.get_match_ids() {
+                let Ok(state) = matches.get_match(match_id) else {
+                    continue;
+                };
+                let addrs: Vec<SocketAddr> = get_players(state)
+                    .into_iter()
+                    .map(|(_, addr)| addr)
+                    .collect();
+                updates.push((addrs, state.get_game_update().await));
+            }
+        }
+
+        let client_handler = self.network_manager.get_client_handler_mut();
+        for (addrs, update) in updates {
+            if let Err(e) = client_handler.send_game_update(&addrs, &update).await {
+                eprintln!("Failed to send game update: {}", e);
+            }
+        }
+    }
+
+    // Resends, keepalives, and dropping clients that went quiet along with
+    // their players
+    async fn housekeeping(&mut self) {
+        let client_handler = self.network_manager.get_client_handler_mut();
+        if let Err(e) = client_handler.resend_pending().await {
+            eprintln!("Failed to resend pending messages: {}", e);
+        }
+        if let Err(e) = client_handler.send_keep_alives(KEEP_ALIVE_INTERVAL).await {
+            eprintln!("Failed to send keepalives: {}", e);
+        }
+
+        let removed = client_handler.remove_inactive_clients(CLIENT_TIMEOUT).await;
+        for addr in removed {
+            self.network_manager
+                .remove_client(addr, DisconnectReason::TimedOut)
+                .await;
+        }
+    }
 }
 
-impl GameServer {}
+fn managers() -> (Arc<Mutex<GameStateManager>>, Arc<Mutex<MatchmakingManager>>) {
+    (
+        Arc::new(Mutex::new(GameStateManager::new())),
+        Arc::new(Mutex::new(MatchmakingManager::new(MATCHMAKING_MAX_WAIT))),
+    )
+}
+
+// Who's playing in a match, to send them what concerns it
+fn get_players(state: &State) -> Vec<(PlayerId, SocketAddr)> {
+    let mut players: Vec<(PlayerId, SocketAddr)> = state
+        .players
+        .get_players()
+        .values()
+        .map(|player| (player.get_id(), player.get_addr()))
+        .collect();
+    players.sort();
+    players
+}
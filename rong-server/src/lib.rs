@@ -1,5 +1,6 @@
 // src/lib.rs
 
 pub mod game;
+pub mod game_server;
 pub mod matchmaking;
 pub mod network;
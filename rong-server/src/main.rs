@@ -1,22 +1,38 @@
-use std::sync::Arc;
-use tokio;
-
-mod game;
-mod game_server;
-mod matchmaking;
-mod network;
-
-use crate::network::GameServer;
+use rong_server::game_server::{GameServer, SERVER_ADDR};
+use rong_shared::transport::NetworkConditions;
+use std::net::SocketAddr;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    // Initialize the game server
-    let game_server = Arc::new(GameServer::new().await?);
+    let args: Vec<String> = std::env::args().collect();
+    // --latency, --jitter, --loss, --duplicate and --reorder simulate a bad network
+    let conditions = NetworkConditions::from_args(args.iter().cloned())?;
+
+    let mut game_server = GameServer::new(SERVER_ADDR.parse()?, conditions).await?;
+    println!("Listening on {}", game_server.local_addr()?);
+
+    // --websocket <addr> also accepts browser clients
+    if let Some(addr) = flag_value(&args, "--websocket") {
+        let addr: SocketAddr = addr.parse()?;
+        game_server.listen_websocket(addr).await?;
+        println!("Accepting WebSocket clients on {}", addr);
+    }
+
+    let shutdown = game_server.get_shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown();
+        }
+    });
 
-    // Run the game server
     game_server.run().await?;
 
     Ok(())
 }
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    args.get(index + 1).map(String::as_str)
+}
@@ -1,5 +1,69 @@
 pub mod queue;
 
+use crate::game::state::State;
+use rong_shared::error::{Result, ServerError};
+use rong_shared::model::SessionToken;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Clients waiting at once, past this JoinQueue is answered with QueueFull
+pub const MAX_QUEUED_PLAYERS: usize = 1024;
+
 pub struct MatchmakingManager {
     queue: queue::MatchmakingSystem,
 }
+
+impl MatchmakingManager {
+    pub fn new(max_wait_time: Duration) -> Self {
+        MatchmakingManager {
+            queue: queue::MatchmakingSystem::new(max_wait_time),
+        }
+    }
+
+    // Queues the client, or hands back the session it already queued with
+    pub fn join(&mut self, addr: SocketAddr) -> std::result::Result<SessionToken, ServerError> {
+        if let Some(session) = self.queue.get_session(addr) {
+            return Ok(session);
+        }
+        if self.queue.get_len() >= MAX_QUEUED_PLAYERS {
+            return Err(ServerError::QueueFull);
+        }
+        let session = SessionToken::new(rand::random());
+        self.queue.add_player(addr, session);
+        Ok(session)
+    }
+
+    pub fn leave(&mut self, addr: SocketAddr) -> Option<SessionToken> {
+        self.queue.remove_player(addr)
+    }
+
+    pub fn get_session(&self, addr: SocketAddr) -> Option<SessionToken> {
+        self.queue.get_session(addr)
+    }
+
+    // Matches made since the last call, ready to be hosted
+    pub async fn update(&mut self) -> Result<Vec<State>> {
+        self.queue.update().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_past_the_cap_is_queue_full() {
+        let mut manager = MatchmakingManager::new(Duration::from_secs(5));
+        let addr = |port: usize| -> SocketAddr { format!("127.0.0.1:{}", port).parse().unwrap() };
+        for port in 1..=MAX_QUEUED_PLAYERS {
+            manager.join(addr(port)).unwrap();
+        }
+
+        assert!(matches!(
+            manager.join(addr(MAX_QUEUED_PLAYERS + 1)),
+            Err(ServerError::QueueFull)
+        ));
+        // Already queued, so it keeps its place rather than being turned away
+        assert_eq!(manager.join(addr(1)).ok(), manager.get_session(addr(1)));
+    }
+}
@@ -1,13 +1,16 @@
 use crate::game;
 use crate::game::player::player_manager::PlayerManager;
-use crate::game::player::Player;
 use rong_shared::error::Result;
-use rong_shared::model::PlayerId;
+use rong_shared::model::{PlayerId, SessionToken};
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+// A client waiting for a match. Its session is handed out on joining, the
+// match it ends up in accepts the same one
 pub struct QueuedPlayer {
-    player: Player,
+    addr: SocketAddr,
+    session: SessionToken,
     join_time: Instant,
 }
 
@@ -24,25 +27,29 @@ impl MatchmakingQueue {
         }
     }
 
-    pub fn add_player(&mut self, player: Player) {
+    pub fn add_player(&mut self, addr: SocketAddr, session: SessionToken) {
         self.queue.push_back(QueuedPlayer {
-            player,
+            addr,
+            session,
             join_time: Instant::now(),
         });
     }
 
-    pub fn remove_player(&mut self, player_id: PlayerId) -> Option<Player> {
-        if let Some(index) = self
-            .queue
-            .iter()
-            .position(|qp| qp.player.get_id() == player_id)
-        {
-            Some(self.queue.remove(index).unwrap().player)
+    pub fn remove_player(&mut self, addr: SocketAddr) -> Option<SessionToken> {
+        if let Some(index) = self.queue.iter().position(|qp| qp.addr == addr) {
+            Some(self.queue.remove(index).unwrap().session)
         } else {
             None
         }
     }
 
+    pub fn get_session(&self, addr: SocketAddr) -> Option<SessionToken> {
+        self.queue
+            .iter()
+            .find(|qp| qp.addr == addr)
+            .map(|qp| qp.session)
+    }
+
     pub async fn create_matches(&mut self) -> Vec<game::state::State> {
         let mut matches = Vec::new();
         let now = Instant::now();
@@ -50,47 +57,15 @@ impl MatchmakingQueue {
         while self.queue.len() >= 2 {
             let player1 = self.queue.pop_front().unwrap();
             if let Some(player2) = self.find_suitable_match(&player1) {
-                let mut player_manager = PlayerManager::new(std::sync::Arc::new(
-                    tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap(),
-                ));
-                if let Err(e) = player_manager
-                    .add_player(player1.player.get_id(), player1.player.get_addr())
-                    .await
-                {
-                    eprintln!("Failed to add player1: {:?}", e);
-                    continue;
-                }
-                if let Err(e) = player_manager
-                    .add_player(player2.get_id(), player2.get_addr())
-                    .await
-                {
-                    eprintln!("Failed to add player2: {:?}", e);
-                    continue;
+                if let Some(game_state) = create_match(&player1, &player2).await {
+                    matches.push(game_state);
                 }
-                let game_state = game::state::State::new(player_manager);
-                matches.push(game_state);
             } else if now.duration_since(player1.join_time) > self.max_wait_time {
                 // If player has waited too long, match with next available player
                 if let Some(player2) = self.queue.pop_front() {
-                    let mut player_manager = PlayerManager::new(std::sync::Arc::new(
-                        tokio::net::UdpSocket::bind("0.0.0.0:0").await.unwrap(),
-                    ));
-                    if let Err(e) = player_manager
-                        .add_player(player1.player.get_id(), player1.player.get_addr())
-                        .await
-                    {
-                        eprintln!("Failed to add player1: {:?}", e);
-                        continue;
-                    }
-                    if let Err(e) = player_manager
-                        .add_player(player2.player.get_id(), player2.player.get_addr())
-                        .await
-                    {
-                        eprintln!("Failed to add player2: {:?}", e);
-                        continue;
+                    if let Some(game_state) = create_match(&player1, &player2).await {
+                        matches.push(game_state);
                     }
-                    let game_state = game::state::State::new(player_manager);
-                    matches.push(game_state);
                 } else {
                     // No other players available, put back in queue
                     self.queue.push_front(player1);
@@ -104,21 +79,43 @@ impl MatchmakingQueue {
         matches
     }
 
-    fn find_suitable_match(&mut self, _player: &QueuedPlayer) -> Option<Player> {
+    fn find_suitable_match(&mut self, _player: &QueuedPlayer) -> Option<QueuedPlayer> {
         // For simplicity, we're just matching with the next player in queue
         // In a more advanced system, you could consider factors like skill level
-        self.queue.pop_front().map(|qp| qp.player)
+        self.queue.pop_front()
     }
 
-    pub fn get_queue_status(&self) -> Vec<(PlayerId, Duration)> {
+    pub fn get_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn get_queue_status(&self) -> Vec<(SocketAddr, Duration)> {
         let now = Instant::now();
         self.queue
             .iter()
-            .map(|qp| (qp.player.get_id(), now.duration_since(qp.join_time)))
+            .map(|qp| (qp.addr, now.duration_since(qp.join_time)))
             .collect()
     }
 }
 
+// Seats the first player as PLAYER_1 and the second as PLAYER_2
+async fn create_match(
+    player1: &QueuedPlayer,
+    player2: &QueuedPlayer,
+) -> Option<game::state::State> {
+    let mut player_manager = PlayerManager::new();
+    for (id, player) in [(PlayerId::PLAYER_1, player1), (PlayerId::PLAYER_2, player2)] {
+        if let Err(e) = player_manager
+            .add_player_with_session(id, player.addr, player.session)
+            .await
+        {
+            eprintln!("Failed to add {:?}: {:?}", id, e);
+            return None;
+        }
+    }
+    Some(game::state::State::new(player_manager))
+}
+
 pub struct MatchmakingSystem {
     queue: MatchmakingQueue,
 }
@@ -134,15 +131,23 @@ impl MatchmakingSystem {
         Ok(self.queue.create_matches().await)
     }
 
-    pub fn add_player(&mut self, player: Player) {
-        self.queue.add_player(player);
+    pub fn add_player(&mut self, addr: SocketAddr, session: SessionToken) {
+        self.queue.add_player(addr, session);
+    }
+
+    pub fn remove_player(&mut self, addr: SocketAddr) -> Option<SessionToken> {
+        self.queue.remove_player(addr)
+    }
+
+    pub fn get_session(&self, addr: SocketAddr) -> Option<SessionToken> {
+        self.queue.get_session(addr)
     }
 
-    pub fn remove_player(&mut self, player_id: PlayerId) -> Option<Player> {
-        self.queue.remove_player(player_id)
+    pub fn get_len(&self) -> usize {
+        self.queue.get_len()
     }
 
-    pub fn get_queue_status(&self) -> Vec<(PlayerId, Duration)> {
+    pub fn get_queue_status(&self) -> Vec<(SocketAddr, Duration)> {
         self.queue.get_queue_status()
     }
 }
@@ -1,157 +1,921 @@
-mod connection;
+mod websocket;
 
-use bincode;
-use rong_shared::model::{ClientMessage, NetworkPacket, ServerMessage};
+pub use websocket::WebSocketPeers;
+
+use rong_shared::error::{CodecError, DecodeError};
+#[cfg(feature = "secure")]
+use rong_shared::model::PublicKey;
+use rong_shared::model::{
+    ClientMessage, DisconnectReason, GameUpdateData, NetworkPacket, ServerMessage, PROTOCOL_VERSION,
+};
+#[cfg(not(feature = "secure"))]
+use rong_shared::transport::WireCodec;
+use rong_shared::transport::{
+    now_millis, open_datagram, ClockSync, DefaultCodec, DroppedPackets, Fragmenter, Frame,
+    NetworkConditions, Reassembler, ReliableEndpoint, SendRate, SendRateConfig, SimulatedTransport,
+    SnapshotEncoder, Transport, RECEIVE_BUFFER_SIZE,
+};
+#[cfg(feature = "secure")]
+use rong_shared::transport::{open_plain, KeyExchange, Opened, Role, SecureChannel};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
 use tokio::sync::{mpsc, Mutex};
 
-pub struct ClientHandler {
-    socket: Arc<UdpSocket>,
+// Datagrams from WebSocket clients waiting to be processed
+const WEBSOCKET_INBOUND_SIZE: usize = 1024;
+// Peers we hold state for that haven't sent a valid Hello yet. Past this,
+// newcomers are ignored until some greet us or time out
+const MAX_PENDING_CLIENTS: usize = 64;
+
+type Inbound = (Vec<u8>, SocketAddr);
+
+// Generic over the transport so tests can run on a MemoryNetwork instead of real ports
+pub struct ClientHandler<T: Transport = UdpSocket> {
+    socket: Arc<T>,
+    // Browser clients, see listen_websocket
+    websocket_peers: WebSocketPeers,
+    websocket_sender: mpsc::Sender<Inbound>,
+    websocket_inbound: Arc<Mutex<mpsc::Receiver<Inbound>>>,
     clients: HashMap<SocketAddr, ClientInfo>,
-    packet_sender: mpsc::Sender<(NetworkPacket<ClientMessage>, SocketAddr)>,
+    // Packets sent so far, wrapping like every other counter on the wire
     sequence: u32,
+    dropped: DroppedPackets,
+    fragmenter: Fragmenter,
+    // Bounds each client's GameUpdate rate adapts within
+    send_rate_config: SendRateConfig,
 }
 
-#[derive(Clone)]
 pub struct ClientInfo {
     last_seen: Instant,
+    // Last time anything went out to them, see send_keep_alives
+    last_sent: Instant,
+    reliability: ReliableEndpoint<ServerMessage, ClientMessage>,
+    snapshots: SnapshotEncoder,
+    send_rate: SendRate,
+    reassembler: Reassembler,
+    clock: ClockSync,
+    // Set by a Hello on our protocol version. Fragments are only reassembled
+    // after it, so a stranger can't make us buffer partial messages
+    greeted: bool,
+    // Set once the client's key exchange arrives, everything after is sealed
+    #[cfg(feature = "secure")]
+    secure: Option<SecureChannel>,
 }
 
 impl ClientHandler {
-    pub async fn new(
+    pub async fn new(server_addr: SocketAddr) -> Result<Self, std::io::Error> {
+        let socket = UdpSocket::bind(server_addr).await?;
+        Ok(ClientHandler::with_transport(socket))
+    }
+}
+
+impl ClientHandler<SimulatedTransport<UdpSocket>> {
+    // Sends to clients over a simulated bad network, for reproducing lag and loss
+    pub async fn with_conditions(
         server_addr: SocketAddr,
-        packet_sender: mpsc::Sender<(NetworkPacket<ClientMessage>, SocketAddr)>,
+        conditions: NetworkConditions,
     ) -> Result<Self, std::io::Error> {
-        let socket = Arc::new(UdpSocket::bind(server_addr).await?);
+        let socket = UdpSocket::bind(server_addr).await?;
+        Ok(ClientHandler::with_transport(SimulatedTransport::new(
+            socket, conditions,
+        )))
+    }
+}
+
+impl<T: Transport> ClientHandler<T> {
+    pub fn with_transport(transport: T) -> Self {
+        let (websocket_sender, websocket_inbound) = mpsc::channel(WEBSOCKET_INBOUND_SIZE);
 
-        Ok(ClientHandler {
-            socket,
+        ClientHandler {
+            socket: Arc::new(transport),
+            websocket_peers: WebSocketPeers::default(),
+            websocket_sender,
+            websocket_inbound: Arc::new(Mutex::new(websocket_inbound)),
             clients: HashMap::new(),
-            packet_sender,
             sequence: 0,
-        })
+            dropped: DroppedPackets::default(),
+            fragmenter: Fragmenter::new(),
+            send_rate_config: SendRateConfig::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.socket.local_addr()
+    }
+
+    // Applies to clients that connect from now on
+    pub fn set_send_rate_config(&mut self, config: SendRateConfig) {
+        self.send_rate_config = config;
+    }
+
+    // Also accept clients over WebSocket on addr. They share the packet
+    // handler, and so matches, with the UDP clients
+    pub async fn listen_websocket(&mut self, addr: SocketAddr) -> Result<(), std::io::Error> {
+        websocket::listen(
+            addr,
+            self.websocket_peers.clone(),
+            self.websocket_sender.clone(),
+        )
+        .await
+    }
+
+    // Call when something arrives from the client, it counts as a sign of life
+    pub fn update_client(&mut self, client_addr: SocketAddr) -> &mut ClientInfo {
+        let client = self.client_entry(client_addr);
+        client.last_seen = Instant::now();
+        client
     }
 
-    pub fn update_client(&mut self, client_addr: SocketAddr) {
+    // Sending to a client doesn't mean it's still there, so this leaves last_seen be
+    fn client_entry(&mut self, client_addr: SocketAddr) -> &mut ClientInfo {
+        let send_rate_config = self.send_rate_config;
         self.clients
             .entry(client_addr)
-            .and_modify(|client| {
-                client.last_seen = Instant::now();
-            })
-            .or_insert(ClientInfo {
+            .or_insert_with(|| ClientInfo {
                 last_seen: Instant::now(),
-            });
+                last_sent: Instant::now(),
+                reliability: ReliableEndpoint::default(),
+                snapshots: SnapshotEncoder::new(),
+                send_rate: SendRate::new(send_rate_config),
+                reassembler: Reassembler::new(),
+                clock: ClockSync::new(),
+                greeted: false,
+                #[cfg(feature = "secure")]
+                secure: None,
+            })
     }
 
-    pub fn remove_inactive_clients(&mut self, timeout: Duration) {
+    // Drops clients that have been silent longer than timeout, or that never
+    // acked a reliable message through all its resends, telling them why in
+    // case they can still hear us. Returns who was dropped, so their players
+    // can be taken out of the game too
+    pub async fn remove_inactive_clients(&mut self, timeout: Duration) -> Vec<SocketAddr> {
         let now = Instant::now();
-        self.clients
-            .retain(|_, client| now.duration_since(client.last_seen) <= timeout);
+        let inactive: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                now.duration_since(client.last_seen) > timeout || client.reliability.has_failed()
+            })
+            .map(|(&addr, _)| addr)
+            .collect();
+
+        for &addr in &inactive {
+            self.disconnect(addr, DisconnectReason::TimedOut).await;
+        }
+        for client in self.clients.values_mut() {
+            client.reassembler.expire(now);
+        }
+        inactive
+    }
+
+    // Sends the client a Disconnect and forgets it
+    pub async fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) {
+        if !self.clients.contains_key(&addr) {
+            return;
+        }
+        if let Err(e) = self
+            .send_to(&ServerMessage::Disconnect { reason }, addr)
+            .await
+        {
+            eprintln!("Failed to send disconnect to {}: {}", addr, e);
+        }
+        self.clients.remove(&addr);
+    }
+
+    // Tells every client why they're being let go, e.g. on shutdown
+    pub async fn disconnect_all(&mut self, reason: DisconnectReason) {
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for addr in addrs {
+            self.disconnect(addr, reason).await;
+        }
+    }
+
+    // Sends a KeepAlive to every client we haven't sent anything to within
+    // interval, so an idle match doesn't look like a dead server
+    pub async fn send_keep_alives(&mut self, interval: Duration) -> Result<(), std::io::Error> {
+        let now = Instant::now();
+        let idle: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| now.duration_since(client.last_sent) >= interval)
+            .map(|(&addr, _)| addr)
+            .collect();
+        // One unreachable client mustn't cut the rest off, the first failure
+        // is still returned once everyone was tried
+        let mut result = Ok(());
+        for addr in idle {
+            let sent = self.send_to(&ServerMessage::KeepAlive, addr).await;
+            result = result.and(sent);
+        }
+        result
+    }
+
+    pub async fn broadcast(&mut self, message: &ServerMessage) -> Result<(), std::io::Error> {
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        let mut result = Ok(());
+        for addr in addrs {
+            let sent = self.send_to(message, addr).await;
+            result = result.and(sent);
+        }
+        result
     }
 
-    pub async fn broadcast(
+    // Each client gets the update as a delta against the last snapshot it acked,
+    // at its own SendRate. Meant to be called every tick, clients that aren't
+    // due an update yet are skipped
+    pub async fn broadcast_game_update(
         &mut self,
-        packet: &NetworkPacket<ServerMessage>,
+        data: &GameUpdateData,
     ) -> Result<(), std::io::Error> {
-        let serialized = bincode::serialize(packet)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        self.send_game_update(&addrs, data).await
+    }
 
-        for &addr in self.clients.keys() {
-            if let Err(e) = self.socket.send_to(&serialized, addr).await {
-                eprintln!("Failed to broadcast to client: {}", e);
-            } else {
-                self.sequence += 1;
-            }
+    // Same as broadcast_game_update, for the players of one match
+    pub async fn send_game_update(
+        &mut self,
+        addrs: &[SocketAddr],
+        data: &GameUpdateData,
+    ) -> Result<(), std::io::Error> {
+        let now = Instant::now();
+        let due: Vec<SocketAddr> = addrs
+            .iter()
+            .copied()
+            .filter(|addr| {
+                self.clients
+                    .get(addr)
+                    .is_some_and(|client| client.send_rate.is_due(now))
+            })
+            .collect();
+
+        let mut result = Ok(());
+        for addr in due {
+            let client = self.client_entry(addr);
+            let tolerance = client.send_rate.get_position_tolerance();
+            let snapshot = client.snapshots.encode_within(data.clone(), tolerance);
+            client.send_rate.record_sent(snapshot.get_id(), now);
+            client.send_rate.adapt(client.clock.rtt());
+
+            let sent = self
+                .send_to(&ServerMessage::GameUpdate(snapshot), addr)
+                .await;
+            result = result.and(sent);
         }
-        Ok(())
+        result
+    }
+
+    // How often the client is getting updates, and how much loss that's seeing
+    pub fn get_send_rate(&self, addr: SocketAddr) -> Option<&SendRate> {
+        self.clients.get(&addr).map(|client| &client.send_rate)
     }
 
     pub async fn send_to(
+        &mut self,
+        message: &ServerMessage,
+        addr: SocketAddr,
+    ) -> Result<(), std::io::Error> {
+        let timestamp = self.get_timestamp();
+        let packet =
+            self.client_entry(addr)
+                .reliability
+                .send(message.clone(), timestamp, Instant::now());
+
+        self.send_packet(&packet, addr).await
+    }
+
+    // Probe every client's round trip time and clock, answered by a Pong
+    pub async fn ping_clients(&mut self) -> Result<(), std::io::Error> {
+        // Secure builds wait for the key exchange, a ping never goes out in the clear
+        #[cfg(feature = "secure")]
+        let addrs: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.secure.is_some())
+            .map(|(&addr, _)| addr)
+            .collect();
+        #[cfg(not(feature = "secure"))]
+        let addrs: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        let mut result = Ok(());
+        for addr in addrs {
+            let sent = self.send_to(&ServerMessage::Ping, addr).await;
+            result = result.and(sent);
+        }
+        result
+    }
+
+    // Round trip time and clock offset for one client, the offset being the
+    // client's clock minus ours
+    pub fn get_clock_sync(&self, addr: SocketAddr) -> Option<&ClockSync> {
+        self.clients.get(&addr).map(|client| &client.clock)
+    }
+
+    // Re-send reliable messages that clients haven't acknowledged yet
+    pub async fn resend_pending(&mut self) -> Result<(), std::io::Error> {
+        let timestamp = self.get_timestamp();
+        let now = Instant::now();
+
+        let mut due = Vec::new();
+        for (&addr, client) in self.clients.iter_mut() {
+            for packet in client.reliability.retransmit(timestamp, now) {
+                due.push((packet, addr));
+            }
+        }
+
+        let mut result = Ok(());
+        for (packet, addr) in due {
+            let sent = self.send_packet(&packet, addr).await;
+            result = result.and(sent);
+        }
+        result
+    }
+
+    async fn send_packet(
         &mut self,
         packet: &NetworkPacket<ServerMessage>,
         addr: SocketAddr,
     ) -> Result<(), std::io::Error> {
-        let serialized = bincode::serialize(packet)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let datagrams = self.encode_for(packet, addr)?;
 
-        if let Err(e) = self.socket.send_to(&serialized, addr).await {
-            eprintln!("Failed to broadcast to client: {}", e);
-        } else {
-            self.sequence += 1;
+        for datagram in datagrams {
+            self.send_datagram(&datagram, addr).await?;
         }
+        if let Some(client) = self.clients.get_mut(&addr) {
+            client.last_sent = Instant::now();
+        }
+        self.sequence = self.sequence.wrapping_add(1);
         Ok(())
     }
 
-    pub async fn receive(&mut self) -> Option<(ClientMessage, std::net::SocketAddr)> {
-        let mut buf = [0; 1024];
-        match self.socket.recv_from(&mut buf).await {
-            Ok((size, addr)) => {
-                self.update_client(addr);
-                match bincode::deserialize::<NetworkPacket<ClientMessage>>(&buf[..size]) {
-                    Ok(packet) => {
-                        if let Err(e) = self.packet_sender.send((packet.clone(), addr)).await {
-                            eprintln!("Failed to send packet to handler: {}", e);
-                        }
-                        Some((packet.get_payload().clone(), addr))
-                    }
+    async fn send_datagram(&self, datagram: &[u8], addr: SocketAddr) -> Result<(), std::io::Error> {
+        if !self.websocket_peers.send(datagram, addr).await {
+            self.socket.send_to(datagram, addr).await?;
+        }
+        Ok(())
+    }
+
+    // Next datagram from either a UDP or a WebSocket client. Cancel safe, so
+    // it can wait in a select! next to timers
+    pub async fn next_datagram(&self) -> Result<(Vec<u8>, SocketAddr), std::io::Error> {
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+        let mut websocket_inbound = self.websocket_inbound.lock().await;
+        tokio::select! {
+            received = self.socket.recv_from(&mut buf) => {
+                let (size, addr) = received?;
+                Ok((buf[..size].to_vec(), addr))
+            }
+            // The sender lives in self, so this never runs dry
+            Some(received) = websocket_inbound.recv() => Ok(received),
+        }
+    }
+
+    #[cfg(not(feature = "secure"))]
+    fn encode_for(
+        &mut self,
+        packet: &NetworkPacket<ServerMessage>,
+        _addr: SocketAddr,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        self.fragmenter.encode::<DefaultCodec, _>(packet)
+    }
+
+    #[cfg(feature = "secure")]
+    fn encode_for(
+        &mut self,
+        packet: &NetworkPacket<ServerMessage>,
+        addr: SocketAddr,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        match self
+            .clients
+            .get(&addr)
+            .and_then(|client| client.secure.as_ref())
+        {
+            Some(channel) => {
+                let sealed = channel.seal::<DefaultCodec, _>(packet)?;
+                self.fragmenter.encode_body(&sealed)
+            }
+            None => self.fragmenter.encode::<DefaultCodec, _>(packet),
+        }
+    }
+
+    // Answers the client's public key with ours. A retry from a client that
+    // missed the reply gets the same answer, anything else is ignored
+    #[cfg(feature = "secure")]
+    async fn exchange_keys(
+        &mut self,
+        client_key: PublicKey,
+        addr: SocketAddr,
+    ) -> Result<(), std::io::Error> {
+        let client = self.update_client(addr);
+        let server_key = match &client.secure {
+            Some(channel) if channel.remote_key() == client_key => channel.local_key(),
+            Some(_) => {
+                eprintln!("Ignored a second key exchange from {}", addr);
+                return Ok(());
+            }
+            None => {
+                let exchange = KeyExchange::new();
+                let server_key = exchange.public_key();
+                match exchange.finish(Role::Server, client_key) {
+                    Ok(channel) => client.secure = Some(channel),
                     Err(e) => {
-                        eprintln!("Failed to deserialize packet: {}", e);
-                        None
+                        eprintln!("Key exchange with {} failed: {}", addr, e);
+                        return Ok(());
                     }
                 }
+                server_key
+            }
+        };
+
+        // Unsealed, the client can't open anything until it has our key
+        let reply = NetworkPacket::new(
+            0,
+            self.get_timestamp(),
+            ServerMessage::KeyExchange(server_key),
+        );
+        for datagram in self.fragmenter.encode::<DefaultCodec, _>(&reply)? {
+            self.send_datagram(&datagram, addr).await?;
+        }
+        Ok(())
+    }
+
+    // Takes in a datagram from next_datagram and runs it through the client's
+    // transport state, returning what the packet handler should see. One
+    // datagram can release many buffered reliable messages at once
+    pub async fn accept(
+        &mut self,
+        datagram: &[u8],
+        addr: SocketAddr,
+    ) -> Vec<NetworkPacket<ClientMessage>> {
+        let Some(packet) = self.decode(datagram, addr) else {
+            return Vec::new();
+        };
+        if !self.clients.contains_key(&addr) && self.get_pending_count() >= MAX_PENDING_CLIENTS {
+            eprintln!("Ignored {}, too many clients mid-handshake", addr);
+            return Vec::new();
+        }
+
+        #[cfg(feature = "secure")]
+        if let ClientMessage::KeyExchange(client_key) = packet.get_payload() {
+            if let Err(e) = self.exchange_keys(*client_key, addr).await {
+                eprintln!("Failed to answer key exchange from {}: {}", addr, e);
+            }
+            return Vec::new();
+        }
+
+        let mut ready = Vec::new();
+        for packet in self.deliver(packet, addr) {
+            if let ClientMessage::Ping = packet.get_payload() {
+                let pong = ServerMessage::Pong(packet.get_timestamp());
+                if let Err(e) = self.send_to(&pong, addr).await {
+                    eprintln!("Failed to answer ping from {}: {}", addr, e);
+                }
+            } else {
+                // The packet handler still sees it, to free the player's seat
+                if let ClientMessage::Disconnect { reason } = packet.get_payload() {
+                    println!("Client {} disconnected: {:?}", addr, reason);
+                    self.clients.remove(&addr);
+                }
+                ready.push(packet);
+            }
+        }
+        ready
+    }
+
+    // Waits for the next datagram and takes it in, see accept
+    pub async fn receive(
+        &mut self,
+    ) -> Result<(Vec<NetworkPacket<ClientMessage>>, SocketAddr), std::io::Error> {
+        let (datagram, addr) = self.next_datagram().await?;
+        Ok((self.accept(&datagram, addr).await, addr))
+    }
+
+    fn decode(
+        &mut self,
+        datagram: &[u8],
+        addr: SocketAddr,
+    ) -> Option<NetworkPacket<ClientMessage>> {
+        let decoded = match open_datagram(datagram) {
+            Ok(Frame::Whole(body)) => self.open_body(body, addr),
+            // Only clients past the handshake get reassembly state
+            Ok(Frame::Fragment(header, chunk)) => match self.clients.get_mut(&addr) {
+                Some(client) if client.greeted => {
+                    client.last_seen = Instant::now();
+                    client
+                        .reassembler
+                        .accept(header, chunk, Instant::now())
+                        .and_then(|body| match body {
+                            Some(body) => self.open_body(&body, addr),
+                            None => Ok(None),
+                        })
+                }
+                _ => Err(DecodeError::Malformed(
+                    "Fragment before the handshake".to_string(),
+                )),
+            },
+            Err(e) => Err(e),
+        };
+
+        match decoded {
+            Ok(packet) => packet,
+            Err(DecodeError::Header(e)) => {
+                // Stray traffic, rejected before anything was deserialized
+                self.dropped.record(&e);
+                eprintln!("Dropped datagram from {}: {}", addr, e);
+                None
             }
             Err(e) => {
-                eprintln!("Failed to receive from socket: {}", e);
+                // Usually a client on a different protocol version that
+                // skipped the Hello handshake
+                eprintln!("Failed to deserialize packet from {}: {}", addr, e);
                 None
             }
         }
     }
 
-    pub fn get_sequence(&mut self) -> u32 {
-        self.sequence += 1;
-        return self.sequence;
+    #[cfg(not(feature = "secure"))]
+    fn open_body(
+        &mut self,
+        body: &[u8],
+        _addr: SocketAddr,
+    ) -> Result<Option<NetworkPacket<ClientMessage>>, DecodeError> {
+        DefaultCodec::decode(body).map(Some)
+    }
+
+    // Until the key exchange only Hello may arrive unsealed, after it only a
+    // repeated key exchange may
+    #[cfg(feature = "secure")]
+    fn open_body(
+        &mut self,
+        body: &[u8],
+        addr: SocketAddr,
+    ) -> Result<Option<NetworkPacket<ClientMessage>>, DecodeError> {
+        let channel = self
+            .clients
+            .get_mut(&addr)
+            .and_then(|client| client.secure.as_mut());
+        let sealed = channel.is_some();
+        let opened = match channel {
+            Some(channel) => channel.open::<DefaultCodec, ClientMessage>(body)?,
+            None => open_plain::<DefaultCodec, ClientMessage>(body)?,
+        };
+
+        match opened {
+            Opened::KeyExchange(client_key) => Ok(Some(NetworkPacket::new(
+                0,
+                0,
+                ClientMessage::KeyExchange(client_key),
+            ))),
+            Opened::Packet(packet)
+                if sealed || matches!(packet.get_payload(), ClientMessage::Hello { .. }) =>
+            {
+                Ok(Some(packet))
+            }
+            Opened::Packet(_) => Err(DecodeError::Malformed("Unsealed packet".to_string())),
+        }
+    }
+
+    pub fn get_dropped_packets(&self) -> DroppedPackets {
+        self.dropped
+    }
+
+    // Runs a packet through the client's transport state, returning what the
+    // packet handler should see
+    fn deliver(
+        &mut self,
+        packet: NetworkPacket<ClientMessage>,
+        addr: SocketAddr,
+    ) -> Vec<NetworkPacket<ClientMessage>> {
+        let now = now_millis();
+        let client = self.update_client(addr);
+        let mut ready = client.reliability.receive(packet);
+        ready.retain(|packet| match packet.get_payload() {
+            ClientMessage::SnapshotAck(id) => {
+                client.snapshots.acknowledge(*id);
+                client.send_rate.record_ack(*id);
+                false
+            }
+            ClientMessage::Pong(ping_time) => {
+                client.clock.record(*ping_time, packet.get_timestamp(), now);
+                false
+            }
+            // Arriving at all was the point, last_seen is already updated
+            ClientMessage::KeepAlive => false,
+            ClientMessage::Hello {
+                protocol_version, ..
+            } => {
+                client.greeted |= *protocol_version == PROTOCOL_VERSION;
+                true
+            }
+            _ => true,
+        });
+        ready
+    }
+
+    // Whether the client sent a Hello on our protocol version
+    pub fn is_greeted(&self, addr: SocketAddr) -> bool {
+        self.clients.get(&addr).is_some_and(|client| client.greeted)
+    }
+
+    // Clients we hold state for that haven't sent a valid Hello yet
+    pub fn get_pending_count(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|client| !client.greeted)
+            .count()
     }
 
     pub fn get_timestamp(&self) -> u64 {
-        // Get the current time
-        let now = SystemTime::now();
+        now_millis()
+    }
+}
 
-        // Calculate the duration since the Unix epoch
-        let duration_since_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+// Sealed builds turn away the unsealed packets these tests send
+#[cfg(all(test, not(feature = "secure")))]
+mod tests {
+    use super::*;
+    use rong_shared::transport::{decode_datagram, encode_datagram, MemoryNetwork};
 
-        // Convert the duration to milliseconds and return it as u64
-        duration_since_epoch.as_millis() as u64
+    async fn send(
+        client: &impl Transport,
+        server: SocketAddr,
+        packet: NetworkPacket<ClientMessage>,
+    ) {
+        let datagram = encode_datagram::<DefaultCodec, _>(&packet).unwrap();
+        client.send_to(&datagram, server).await.unwrap();
     }
 
-    pub async fn run(&mut self) -> Result<(), std::io::Error> {
-        loop {
-            let mut buf = [0; 1024];
-            let (size, addr) = self.socket.recv_from(&mut buf).await?;
+    #[tokio::test]
+    async fn test_memory_network_round_trip() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
 
-            self.update_client(addr);
-            match bincode::deserialize::<NetworkPacket<ClientMessage>>(&buf[..size]) {
-                Ok(packet) => {
-                    if let Err(e) = self.packet_sender.send((packet, addr)).await {
-                        eprintln!("Failed to send packet to handler: {}", e);
-                    }
-                }
-                Err(e) => eprintln!("Failed to deserialize packet: {}", e),
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(1, 0, ClientMessage::JoinQueue),
+        )
+        .await;
+        let (packets, from) = handler.receive().await.unwrap();
+        assert!(matches!(packets[0].get_payload(), ClientMessage::JoinQueue));
+        assert_eq!(from, client.local_addr().unwrap());
+
+        // Pings are answered by the handler itself
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(2, 1234, ClientMessage::Ping),
+        )
+        .await;
+        let (packets, _) = handler.receive().await.unwrap();
+        assert!(packets.is_empty(), "Ping shouldn't reach the handler");
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+        let (size, _) = client.recv_from(&mut buf).await.unwrap();
+        let reply: NetworkPacket<ServerMessage> =
+            decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+        assert!(matches!(reply.get_payload(), ServerMessage::Pong(1234)));
+    }
+
+    #[tokio::test]
+    async fn test_inactive_client_is_told_why() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(1, 0, ClientMessage::KeepAlive),
+        )
+        .await;
+        let (packets, _) = handler.receive().await.unwrap();
+        assert!(packets.is_empty(), "KeepAlive shouldn't reach the handler");
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let removed = handler
+            .remove_inactive_clients(Duration::from_millis(1))
+            .await;
+        assert_eq!(removed, vec![client.local_addr().unwrap()]);
+
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+        let (size, _) = client.recv_from(&mut buf).await.unwrap();
+        let notice: NetworkPacket<ServerMessage> =
+            decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+        assert!(matches!(
+            notice.get_payload(),
+            ServerMessage::Disconnect {
+                reason: DisconnectReason::TimedOut
             }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fragments_wait_for_the_handshake() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        // Too big for one datagram, so it goes out in fragments
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "x".repeat(3000),
+        };
+        let datagrams = Fragmenter::new()
+            .encode::<DefaultCodec, _>(&NetworkPacket::new(1, 0, hello))
+            .unwrap();
+        assert!(datagrams.len() > 1);
+        for datagram in &datagrams[..datagrams.len() - 1] {
+            client.send_to(datagram, server_addr).await.unwrap();
+            handler.receive().await.unwrap();
+        }
+        assert!(
+            !handler.clients.contains_key(&client_addr),
+            "A stranger's fragments shouldn't be buffered"
+        );
+
+        // Once greeted, the same client may send fragments
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+        };
+        send(&client, server_addr, NetworkPacket::new(2, 0, hello)).await;
+        let (packets, _) = handler.receive().await.unwrap();
+        assert_eq!(packets.len(), 1);
+        let big = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "x".repeat(3000),
+        };
+        let mut received = Vec::new();
+        for datagram in Fragmenter::new()
+            .encode::<DefaultCodec, _>(&NetworkPacket::new(3, 0, big))
+            .unwrap()
+        {
+            client.send_to(&datagram, server_addr).await.unwrap();
+            received = handler.receive().await.unwrap().0;
         }
+        assert!(matches!(
+            received[0].get_payload(),
+            ClientMessage::Hello { .. }
+        ));
     }
-}
 
-impl Clone for ClientHandler {
-    fn clone(&self) -> Self {
-        ClientHandler {
-            socket: self.socket.clone(),
-            clients: self.clients.clone(),
-            packet_sender: self.packet_sender.clone(),
-            sequence: self.sequence,
+    #[tokio::test]
+    async fn test_only_a_hello_on_our_version_greets() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2907".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(1, 0, ClientMessage::KeepAlive),
+        )
+        .await;
+        handler.receive().await.unwrap();
+        assert!(!handler.is_greeted(client_addr));
+
+        let hello = |protocol_version| ClientMessage::Hello {
+            protocol_version,
+            client_build: "test".to_string(),
+        };
+        send(&client, server_addr, NetworkPacket::new(2, 0, hello(0))).await;
+        handler.receive().await.unwrap();
+        assert!(!handler.is_greeted(client_addr));
+
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(3, 0, hello(PROTOCOL_VERSION)),
+        )
+        .await;
+        handler.receive().await.unwrap();
+        assert!(handler.is_greeted(client_addr));
+    }
+
+    #[tokio::test]
+    async fn test_one_datagram_can_release_many_messages() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        // Reliable ids 1..=100 arrive first and wait for id 0
+        for id in 1..=100 {
+            let mut packet = NetworkPacket::new(id, 0, ClientMessage::JoinQueue);
+            packet.set_reliable_id(Some(id));
+            send(&client, server_addr, packet).await;
+            let (packets, _) = handler.receive().await.unwrap();
+            assert!(packets.is_empty());
+        }
+        let mut first = NetworkPacket::new(101, 0, ClientMessage::JoinQueue);
+        first.set_reliable_id(Some(0));
+        send(&client, server_addr, first).await;
+        let (packets, _) = handler.receive().await.unwrap();
+        assert_eq!(packets.len(), 101);
+    }
+
+    #[tokio::test]
+    async fn test_pending_clients_are_capped() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        for _ in 0..MAX_PENDING_CLIENTS + 1 {
+            let client = network.bind_any().unwrap();
+            send(
+                &client,
+                server_addr,
+                NetworkPacket::new(1, 0, ClientMessage::KeepAlive),
+            )
+            .await;
+            handler.receive().await.unwrap();
+        }
+        assert_eq!(handler.get_pending_count(), MAX_PENDING_CLIENTS);
+        assert_eq!(handler.clients.len(), MAX_PENDING_CLIENTS);
+    }
+
+    // Every send fails, as when the host has no route to the client
+    struct Unreachable;
+
+    impl Transport for Unreachable {
+        async fn send_to(&self, _datagram: &[u8], _addr: SocketAddr) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::NetworkUnreachable.into())
+        }
+
+        async fn recv_from(&self, _buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+            std::future::pending().await
         }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok("10.0.0.1:2906".parse().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_is_reported() {
+        let mut handler = ClientHandler::with_transport(Unreachable);
+        let client_addr = "10.0.0.2:1000".parse().unwrap();
+
+        let sent = handler
+            .send_to(&ServerMessage::KeepAlive, client_addr)
+            .await;
+        assert_eq!(
+            sent.unwrap_err().kind(),
+            std::io::ErrorKind::NetworkUnreachable
+        );
+        assert_eq!(handler.sequence, 0, "Nothing went out");
+    }
+
+    #[tokio::test]
+    async fn test_game_updates_follow_send_rate() {
+        use rong_shared::model::{GameStatus, PositionData, Score, ScoreData};
+
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = network.bind_any().unwrap();
+        let mut handler = ClientHandler::with_transport(server);
+
+        send(
+            &client,
+            server_addr,
+            NetworkPacket::new(1, 0, ClientMessage::JoinQueue),
+        )
+        .await;
+        handler.receive().await.unwrap();
+
+        let data = GameUpdateData::new(
+            PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5)),
+            ScoreData::new(Score::default(), Score::default()),
+            GameStatus::GameStarted,
+        );
+        // Back to back, the second is well inside the client's interval
+        handler.broadcast_game_update(&data).await.unwrap();
+        handler.broadcast_game_update(&data).await.unwrap();
+
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+        let (size, _) = client.recv_from(&mut buf).await.unwrap();
+        let update: NetworkPacket<ServerMessage> =
+            decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+        assert!(matches!(update.get_payload(), ServerMessage::GameUpdate(_)));
+
+        let second =
+            tokio::time::timeout(Duration::from_millis(20), client.recv_from(&mut buf)).await;
+        assert!(second.is_err(), "Client wasn't due a second update");
     }
 }
@@ -0,0 +1,103 @@
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+
+// Datagrams queued per connection while its socket catches up, more are dropped like UDP would
+const OUTBOX_SIZE: usize = 256;
+
+/*
+    Browser clients, which can't open UDP sockets, connect over WebSocket.
+
+    Every binary WebSocket message carries exactly one datagram, framed the
+    same way as over UDP, so ClientHandler runs both kinds of client through
+    the same decoding and packet handler. Connections are keyed by their TCP
+    peer address, the same way UDP clients are keyed by theirs.
+*/
+#[derive(Clone, Default)]
+pub struct WebSocketPeers {
+    outboxes: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>,
+}
+
+impl WebSocketPeers {
+    // False if addr isn't a WebSocket client, in which case it's a UDP one
+    pub async fn send(&self, datagram: &[u8], addr: SocketAddr) -> bool {
+        let outboxes = self.outboxes.lock().await;
+        let Some(outbox) = outboxes.get(&addr) else {
+            return false;
+        };
+        if outbox.try_send(datagram.to_vec()).is_err() {
+            eprintln!("Dropped datagram for WebSocket client {}", addr);
+        }
+        true
+    }
+}
+
+// Binds first so a taken port is reported to the caller, then accepts in the background
+pub async fn listen(
+    addr: SocketAddr,
+    peers: WebSocketPeers,
+    inbound: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(serve(stream, peer, peers.clone(), inbound.clone()));
+                }
+                Err(e) => eprintln!("Failed to accept WebSocket connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    addr: SocketAddr,
+    peers: WebSocketPeers,
+    inbound: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let socket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("WebSocket handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+    let (mut sink, mut source) = socket.split();
+
+    let (outbox, mut pending) = mpsc::channel::<Vec<u8>>(OUTBOX_SIZE);
+    peers.outboxes.lock().await.insert(addr, outbox);
+    let writer = tokio::spawn(async move {
+        while let Some(datagram) = pending.recv().await {
+            if sink.send(Message::Binary(datagram)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(message) = source.next().await {
+        match message {
+            Ok(Message::Binary(datagram)) => {
+                if inbound.send((datagram, addr)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) => break,
+            // Pings are answered by tungstenite, text isn't part of the protocol
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("WebSocket connection to {} failed: {}", addr, e);
+                break;
+            }
+        }
+    }
+
+    peers.outboxes.lock().await.remove(&addr);
+    writer.abort();
+}
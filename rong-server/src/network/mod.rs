@@ -1,28 +1,91 @@
-mod client_handler;
+pub mod client_handler;
 mod packet_handler;
 
-use client_handler::ClientHandler;
+pub use client_handler::ClientHandler;
 use packet_handler::PacketHandler;
 
 use crate::game::GameStateManager;
+use crate::matchmaking::MatchmakingManager;
 
+use rong_shared::model::DisconnectReason;
+use rong_shared::transport::{NetworkConditions, SimulatedTransport, Transport};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
 
-pub struct NetworkManager {
-    game_state_manager: Arc<GameStateManager>,
-    client_handler: ClientHandler,
+/*  Pairs the ClientHandler, which owns the connections, with the PacketHandler
+that acts on what clients say */
+pub struct NetworkManager<T: Transport = UdpSocket> {
+    client_handler: ClientHandler<T>,
     packet_handler: PacketHandler,
 }
 
-impl NetworkManager {
-    pub async fn new(game_state_manager: Arc<GameStateManager>) -> Self {
-        let client_handler = ClientHandler::new().await;
-        let packet_handler = PacketHandler::new(game_state_manager);
+impl NetworkManager<SimulatedTransport<UdpSocket>> {
+    pub async fn with_conditions(
+        server_addr: SocketAddr,
+        conditions: NetworkConditions,
+        game_state_manager: Arc<Mutex<GameStateManager>>,
+        matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
+    ) -> Result<Self, std::io::Error> {
+        let client_handler = ClientHandler::with_conditions(server_addr, conditions).await?;
+        Ok(NetworkManager::new(
+            client_handler,
+            game_state_manager,
+            matchmaking_manager,
+        ))
+    }
+}
 
+impl<T: Transport> NetworkManager<T> {
+    pub fn with_transport(
+        transport: T,
+        game_state_manager: Arc<Mutex<GameStateManager>>,
+        matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
+    ) -> Self {
+        let client_handler = ClientHandler::with_transport(transport);
+        NetworkManager::new(client_handler, game_state_manager, matchmaking_manager)
+    }
+
+    fn new(
+        client_handler: ClientHandler<T>,
+        game_state_manager: Arc<Mutex<GameStateManager>>,
+        matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
+    ) -> Self {
         NetworkManager {
-            game_state_manager,
             client_handler,
-            packet_handler,
+            packet_handler: PacketHandler::new(game_state_manager, matchmaking_manager),
+        }
+    }
+
+    // Runs one datagram through the client handler, then answers whatever it
+    // carried for the packet handler, however many messages that is
+    pub async fn handle_datagram(&mut self, datagram: &[u8], addr: SocketAddr) {
+        for packet in self.client_handler.accept(datagram, addr).await {
+            let greeted = self.client_handler.is_greeted(addr);
+            let Some(reply) = self
+                .packet_handler
+                .handle_packet(packet, addr, greeted)
+                .await
+            else {
+                continue;
+            };
+            if let Err(e) = self.client_handler.send_to(reply.get_payload(), addr).await {
+                eprintln!("Failed to reply to {}: {}", addr, e);
+            }
         }
     }
+
+    // A client the ClientHandler already dropped, e.g. for going quiet
+    pub async fn remove_client(&self, addr: SocketAddr, reason: DisconnectReason) {
+        self.packet_handler.remove_client(addr, reason).await;
+    }
+
+    pub fn get_client_handler(&self) -> &ClientHandler<T> {
+        &self.client_handler
+    }
+
+    pub fn get_client_handler_mut(&mut self) -> &mut ClientHandler<T> {
+        &mut self.client_handler
+    }
 }
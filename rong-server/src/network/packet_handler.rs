@@ -1,98 +1,200 @@
-use crate::game::state::State;
 use rong_shared::{
     error,
-    model::{ClientMessage, Movement, NetworkPacket, PlayerId, ServerMessage},
+    model::{Ack, ClientMessage, DisconnectReason, NetworkPacket, ServerMessage, PROTOCOL_VERSION},
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use crate::game::GameStateManager;
+use crate::matchmaking::MatchmakingManager;
 
 pub struct PacketHandler {
-    game_state_manager: Arc<GameStateManager>,
+    game_state_manager: Arc<Mutex<GameStateManager>>,
+    matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
 }
 
 impl PacketHandler {
-    pub fn new(game_state_manager: Arc<GameStateManager>) -> Self {
-        PacketHandler { game_state_manager }
+    pub fn new(
+        game_state_manager: Arc<Mutex<GameStateManager>>,
+        matchmaking_manager: Arc<Mutex<MatchmakingManager>>,
+    ) -> Self {
+        PacketHandler {
+            game_state_manager,
+            matchmaking_manager,
+        }
+    }
+
+    async fn handle_hello(
+        &self,
+        packet: &NetworkPacket<ClientMessage>,
+        protocol_version: u32,
+        client_build: &str,
+        addr: SocketAddr,
+    ) -> NetworkPacket<ServerMessage> {
+        if protocol_version != PROTOCOL_VERSION {
+            eprintln!(
+                "Rejected client {} (build {}): protocol {} != {}",
+                addr, client_build, protocol_version, PROTOCOL_VERSION
+            );
+            return error_reply(
+                packet,
+                error::ServerError::VersionMismatch {
+                    server: PROTOCOL_VERSION,
+                    client: protocol_version,
+                },
+            );
+        }
+
+        println!(
+            "Client {} (build {}) completed handshake",
+            addr, client_build
+        );
+
+        NetworkPacket::new(
+            packet.get_sequence(),
+            packet.get_timestamp(),
+            ServerMessage::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+            },
+        )
+    }
+
+    // A client that left or was dropped by the ClientHandler. Frees its
+    // place in the queue or its seat in a match
+    pub async fn remove_client(&self, addr: SocketAddr, reason: DisconnectReason) {
+        self.matchmaking_manager.lock().await.leave(addr);
+
+        let mut matches = self.game_state_manager.lock().await;
+        let Some((match_id, player_id)) = matches.find_by_addr(addr) else {
+            return;
+        };
+        if let Ok(state) = matches.get_match_mut(match_id) {
+            if let Err(e) = state.disconnect_player(player_id, reason).await {
+                eprintln!("Failed to remove player {:?}: {}", player_id, e);
+            }
+        }
     }
 
+    // greeted comes from the ClientHandler, which marks a client on its Hello
     pub async fn handle_packet(
         &self,
         packet: NetworkPacket<ClientMessage>,
         addr: SocketAddr,
+        greeted: bool,
     ) -> Option<NetworkPacket<ServerMessage>> {
-        let mut state = self.game_state.lock().await;
+        if let ClientMessage::Hello {
+            protocol_version,
+            client_build,
+        } = packet.get_payload()
+        {
+            return Some(
+                self.handle_hello(&packet, *protocol_version, client_build, addr)
+                    .await,
+            );
+        }
 
-        match packet.get_payload() {
-            ClientMessage::Connect() => {
-                // Figure out which player id to assign
-                let player_id = if state.get_player_count() == 0 {
-                    PlayerId::Player1
-                } else if state.get_player_count() == 1 {
-                    PlayerId::Player2
-                } else {
-                    return Some(NetworkPacket::new(
-                        packet.get_sequence(),
-                        packet.get_timestamp(),
-                        ServerMessage::Error(error::ServerError::GameFull),
-                    ));
-                };
+        // The ClientHandler forgets a client as soon as it says goodbye, so this
+        // can't wait on greeted. Freeing a seat nobody holds does nothing
+        if let ClientMessage::Disconnect { reason } = packet.get_payload() {
+            self.remove_client(addr, *reason).await;
+            return None;
+        }
 
-                if let Err(e) = state.add_player(player_id, addr).await {
-                    eprintln!("Failed to add player: {}", e);
-                    return Some(NetworkPacket::new(
-                        packet.get_sequence(),
-                        packet.get_timestamp(),
-                        ServerMessage::Error(error::ServerError::Io(e.to_string())),
-                    ));
-                }
+        if !greeted {
+            return Some(error_reply(&packet, error::ServerError::HandshakeRequired));
+        }
 
-                println!("Player connected: {:?}", player_id);
+        let mut matches = self.game_state_manager.lock().await;
+        let seat = matches.find_by_addr(addr);
+        if let Some(state) = seat.and_then(|(match_id, _)| matches.get_match_mut(match_id).ok()) {
+            state.players.update_last_seen(addr);
+        }
 
-                if state.get_player_count() == 2 {
-                    if let Err(e) = state.start_game() {
-                        eprintln!("Failed to start game: {}", e);
-                    } else {
-                        println!("Game started");
-                    }
+        match packet.get_payload() {
+            ClientMessage::JoinQueue => {
+                if let Some(state) = seat.and_then(|(match_id, _)| matches.get_match(match_id).ok())
+                {
+                    return Some(error_reply(
+                        &packet,
+                        error::ServerError::InvalidState(state.get_state()),
+                    ));
                 }
+                drop(matches);
 
-                Some(NetworkPacket::new(
-                    packet.get_sequence(),
-                    packet.get_timestamp(),
-                    ServerMessage::PlayerJoined(player_id),
-                ))
-            }
-            ClientMessage::Disconnect(player_id) => {
-                state.players.remove_player(*player_id).await.ok()?;
+                // GameFound follows once the queue pairs this client up
+                let session = match self.matchmaking_manager.lock().await.join(addr) {
+                    Ok(session) => session,
+                    Err(e) => return Some(error_reply(&packet, e)),
+                };
+                println!("Client {} joined the queue", addr);
 
                 Some(NetworkPacket::new(
                     packet.get_sequence(),
                     packet.get_timestamp(),
-                    ServerMessage::PlayerLeft(*player_id),
+                    ServerMessage::Success(Ack::AddedToQueue(session)),
                 ))
             }
-            ClientMessage::MovementCommand(movement_packet) => {
-                let (player_id, movement) = movement_packet.get_payload();
-                state.move_player(*player_id, *movement);
+            ClientMessage::LeaveQueue(session) => {
+                let mut matchmaking = self.matchmaking_manager.lock().await;
+                if matchmaking.get_session(addr) == Some(*session) {
+                    matchmaking.leave(addr);
+                } else {
+                    // Leaving a match that's already been made forfeits it
+                    let state = matches.find_by_session(*session, addr).and_then(
+                        |(match_id, player_id)| Ok((matches.get_match_mut(match_id)?, player_id)),
+                    );
+                    let (state, player_id) = match state {
+                        Ok(found) => found,
+                        Err(e) => return Some(error_reply(&packet, e)),
+                    };
+                    if let Err(e) = state
+                        .disconnect_player(player_id, DisconnectReason::Quit)
+                        .await
+                    {
+                        eprintln!("Failed to remove player {:?}: {}", player_id, e);
+                    }
+                }
 
                 Some(NetworkPacket::new(
                     packet.get_sequence(),
                     packet.get_timestamp(),
-                    ServerMessage::Ack("Movement received".to_string()),
+                    ServerMessage::Success(Ack::RemovedFromQueue),
                 ))
             }
-            ClientMessage::ConnectionAck() => {
-                println!("Connection acknowledged by client");
-                None
-            }
-            ClientMessage::Ack(_) => None,
-            ClientMessage::Error(error) => {
-                println!("Error from client: {:?}", error);
+            ClientMessage::MovementInput(movement_data) => {
+                // Identity and match come from the session, never from the packet itself.
+                // Inputs arrive every frame, so a stale session is dropped rather than
+                // answered with an error per packet
+                let (match_id, player_id) = matches
+                    .find_by_session(movement_data.get_session(), addr)
+                    .ok()?;
+                matches.get_match_mut(match_id).ok()?.queue_input(
+                    player_id,
+                    movement_data.get_input_id(),
+                    movement_data.get_movement().clone(),
+                );
+
+                // Unreliable, GameUpdate's last_inputs is the acknowledgement
                 None
             }
+            ClientMessage::KeepAlive => None,
+            // Hello and Disconnect are handled above, the rest never leave the ClientHandler
+            ClientMessage::Hello { .. }
+            | ClientMessage::Disconnect { .. }
+            | ClientMessage::Ping
+            | ClientMessage::Pong(_)
+            | ClientMessage::SnapshotAck(_)
+            | ClientMessage::KeyExchange(_) => None,
         }
     }
 }
+
+// Game errors are mapped onto their ServerError so the client always gets a code
+fn error_reply(
+    packet: &NetworkPacket<ClientMessage>,
+    err: impl Into<error::ServerError>,
+) -> NetworkPacket<ServerMessage> {
+    let err: error::ServerError = err.into();
+    NetworkPacket::new(packet.get_sequence(), packet.get_timestamp(), err.into())
+}
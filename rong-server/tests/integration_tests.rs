@@ -1,68 +1,34 @@
-use rong_shared::model::{ClientMessage, GameState, NetworkPacket, PlayerId, ServerMessage};
+use rong_shared::model::{GameStatus, PlayerId, Score};
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, Mutex};
-use tokio::time::{sleep, timeout, Duration};
 
 use rong_server::game::player::player_manager::PlayerManager;
 use rong_server::game::state::State;
-use rong_server::network::connection::ConnectionManager;
-
-async fn setup_test_environment() -> (
-    State,
-    mpsc::Sender<(NetworkPacket<ClientMessage>, SocketAddr)>,
-    Arc<UdpSocket>,
-    Arc<Mutex<ConnectionManager>>,
-) {
-    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
-    let player_manager = PlayerManager::new(Arc::clone(&socket));
-    let state = State::new(player_manager);
-
-    // Create a channel for the ConnectionManager
-    let (tx, _rx) = mpsc::channel(100);
-
-    // Create the ConnectionManager
-    let connection_manager = Arc::new(Mutex::new(
-        ConnectionManager::new(Arc::clone(&socket), tx.clone())
-            .await
-            .unwrap(),
-    ));
-
-    // Spawn the ConnectionManager's run loop
-    let cm_clone = Arc::clone(&connection_manager);
-    tokio::spawn(async move {
-        let mut cm = cm_clone.lock().await;
-        if let Err(e) = cm.run().await {
-            eprintln!("Connection manager error: {}", e);
-        }
-    });
 
-    (state, tx, socket, connection_manager)
+async fn setup_test_environment() -> State {
+    State::new(PlayerManager::new())
 }
 
 #[tokio::test]
 async fn test_game_state_transitions() {
-    let (mut state, _tx, _socket, _cm) = setup_test_environment().await;
+    let mut state = setup_test_environment().await;
 
     assert_eq!(
         state.get_state(),
-        GameState::WaitingForPlayers,
+        GameStatus::WaitingForPlayers,
         "Initial state should be WaitingForPlayers"
     );
 
     // Add players
     state
         .add_player(
-            PlayerId::Player1,
+            PlayerId::PLAYER_1,
             "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
         )
         .await
         .unwrap();
     state
         .add_player(
-            PlayerId::Player2,
+            PlayerId::PLAYER_2,
             "127.0.0.1:8081".parse::<SocketAddr>().unwrap(),
         )
         .await
@@ -72,7 +38,7 @@ async fn test_game_state_transitions() {
     state.start_game().unwrap();
     assert_eq!(
         state.get_state(),
-        GameState::GameStarted,
+        GameStatus::GameStarted,
         "Game should have started"
     );
 
@@ -80,26 +46,26 @@ async fn test_game_state_transitions() {
     state.end_game();
     assert_eq!(
         state.get_state(),
-        GameState::GameOver,
+        GameStatus::GameOver,
         "Game should be over"
     );
 }
 
 #[tokio::test]
 async fn test_score_update() {
-    let (mut state, _tx, _socket, _cm) = setup_test_environment().await;
+    let mut state = setup_test_environment().await;
 
     // Add players and start game
     state
         .add_player(
-            PlayerId::Player1,
+            PlayerId::PLAYER_1,
             "127.0.0.1:8080".parse::<SocketAddr>().unwrap(),
         )
         .await
         .unwrap();
     state
         .add_player(
-            PlayerId::Player2,
+            PlayerId::PLAYER_2,
             "127.0.0.1:8081".parse::<SocketAddr>().unwrap(),
         )
         .await
@@ -108,153 +74,393 @@ async fn test_score_update() {
 
     // Initial score should be 0-0
     assert_eq!(
-        state.get_scores().get_payload(),
-        (0, 0),
+        state.get_scores().get_classic(),
+        (Score::new(0), Score::new(0)),
         "Initial scores should be 0-0"
     );
 
     // Simulate a score
-    state.update_score(PlayerId::Player1);
+    state.update_score(PlayerId::PLAYER_1);
     assert_eq!(
-        state.get_scores().get_payload(),
-        (1, 0),
+        state.get_scores().get_classic(),
+        (Score::new(1), Score::new(0)),
         "Player 1 should have scored"
     );
 
-    state.update_score(PlayerId::Player2);
+    state.update_score(PlayerId::PLAYER_2);
     assert_eq!(
-        state.get_scores().get_payload(),
-        (1, 1),
+        state.get_scores().get_classic(),
+        (Score::new(1), Score::new(1)),
         "Both players should have scored"
     );
 }
 
-async fn receive_packet_with_timeout(
-    socket: &UdpSocket,
-    duration: Duration,
-) -> Option<ServerMessage> {
-    timeout(duration, async {
-        let mut buf = [0; 1024];
-        match socket.recv_from(&mut buf).await {
-            Ok((size, _)) => bincode::deserialize::<NetworkPacket<ServerMessage>>(&buf[..size])
-                .ok()
-                .map(|packet| packet.get_payload().clone()),
-            Err(_) => None,
-        }
-    })
-    .await
-    .unwrap_or(None)
-}
+// Sealed builds turn away the unsealed packets sent here
+#[cfg(not(feature = "secure"))]
+mod game_server {
+    use rong_server::game_server::GameServer;
+    use rong_shared::model::{
+        ClientMessage, DisconnectReason, GameEvent, Movement, MovementData, NetworkPacket,
+        PlayerId, ServerMessage, SessionToken, PROTOCOL_VERSION,
+    };
+    use rong_shared::transport::{
+        decode_datagram, encode_datagram, DefaultCodec, MemoryNetwork, MemoryTransport, Transport,
+        RECEIVE_BUFFER_SIZE,
+    };
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use tokio::time::{timeout, Duration};
+
+    // Sequences have to count up, the server drops repeats as duplicates
+    async fn send(
+        client: &MemoryTransport,
+        server: SocketAddr,
+        sequence: u32,
+        message: ClientMessage,
+    ) {
+        let packet = NetworkPacket::new(sequence, 0, message);
+        let datagram = encode_datagram::<DefaultCodec, _>(&packet).unwrap();
+        client.send_to(&datagram, server).await.unwrap();
+    }
 
-#[tokio::test]
-async fn test_game_start_and_state_update() {
-    let test_timeout = Duration::from_secs(10);
-    let result = timeout(test_timeout, async {
-        let (mut state, _tx, _server_socket, connection_manager) = setup_test_environment().await;
-
-        // Create client sockets
-        let client1_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
-        let client2_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
-
-        // Add players
-        state
-            .add_player(PlayerId::Player1, client1_socket.local_addr().unwrap())
-            .await
-            .unwrap();
-        state
-            .add_player(PlayerId::Player2, client2_socket.local_addr().unwrap())
-            .await
-            .unwrap();
+    // Next message from the server that matches, skipping anything else
+    async fn expect(
+        client: &MemoryTransport,
+        matches: impl Fn(&ServerMessage) -> bool,
+    ) -> ServerMessage {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let mut buf = [0; RECEIVE_BUFFER_SIZE];
+                let (size, _) = client.recv_from(&mut buf).await.unwrap();
+                let packet: NetworkPacket<ServerMessage> =
+                    decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+                if matches(packet.get_payload()) {
+                    return packet.get_payload().clone();
+                }
+            }
+        })
+        .await
+        .expect("server never sent the expected message")
+    }
 
-        // Create a channel to monitor outgoing messages from the server
-        let (monitor_tx, mut monitor_rx) = mpsc::channel(100);
-        let monitor_tx = Arc::new(Mutex::new(monitor_tx));
+    // Everything the server sends within duration
+    async fn receive_for(client: &MemoryTransport, duration: Duration) -> Vec<ServerMessage> {
+        let mut received = Vec::new();
+        let _ = timeout(duration, async {
+            loop {
+                let mut buf = [0; RECEIVE_BUFFER_SIZE];
+                let (size, _) = client.recv_from(&mut buf).await.unwrap();
+                let packet: NetworkPacket<ServerMessage> =
+                    decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+                received.push(packet.get_payload().clone());
+            }
+        })
+        .await;
+        received
+    }
+
+    // Handshakes and queues up, using sequences 1 and 2
+    async fn join(client: &MemoryTransport, server: SocketAddr) {
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+        };
+        send(client, server, 1, hello).await;
+        expect(client, |message| {
+            matches!(message, ServerMessage::Welcome { .. })
+        })
+        .await;
+        send(client, server, 2, ClientMessage::JoinQueue).await;
+        expect(client, |message| {
+            matches!(message, ServerMessage::Success(_))
+        })
+        .await;
+    }
 
-        // Set up the message monitor in the ConnectionManager
-        {
-            let mut cm = connection_manager.lock().await;
-            cm.set_message_monitor(Arc::clone(&monitor_tx));
+    #[tokio::test]
+    async fn test_hosts_a_match_and_shuts_down() {
+        let network = MemoryNetwork::new();
+        let server =
+            GameServer::with_transport(network.bind("10.0.0.1:2906".parse().unwrap()).unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let shutdown = server.get_shutdown_handle();
+        let running = tokio::spawn(server.run());
+
+        let clients = [network.bind_any().unwrap(), network.bind_any().unwrap()];
+        for client in &clients {
+            join(client, server_addr).await;
         }
 
-        // Start the game
-        state.start_game().unwrap();
+        for client in &clients {
+            expect(client, |message| {
+                matches!(message, ServerMessage::GameFound(_))
+            })
+            .await;
+            expect(client, |message| {
+                matches!(message, ServerMessage::GameUpdate(_))
+            })
+            .await;
+        }
 
-        // Update state to trigger broadcast
-        state.update().await.unwrap();
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
+        for client in &clients {
+            let disconnect = expect(client, |message| {
+                matches!(message, ServerMessage::Disconnect { .. })
+            })
+            .await;
+            assert!(matches!(
+                disconnect,
+                ServerMessage::Disconnect {
+                    reason: DisconnectReason::ServerShutdown
+                }
+            ));
+        }
+    }
 
-        // Check for messages with a timeout
-        let messages_received = Arc::new(AtomicUsize::new(0));
-        let messages_received_clone = Arc::clone(&messages_received);
+    #[tokio::test]
+    async fn test_hosts_concurrent_matches() {
+        let network = MemoryNetwork::new();
+        let server =
+            GameServer::with_transport(network.bind("10.0.0.1:2906".parse().unwrap()).unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let shutdown = server.get_shutdown_handle();
+        let running = tokio::spawn(server.run());
+
+        // Queued in order, so the first two meet and so do the last two
+        let clients: Vec<MemoryTransport> = (0..4).map(|_| network.bind_any().unwrap()).collect();
+        for client in &clients {
+            join(client, server_addr).await;
+        }
+        let mut seats = Vec::new();
+        for client in &clients {
+            if let ServerMessage::GameFound(player_id) = expect(client, |message| {
+                matches!(message, ServerMessage::GameFound(_))
+            })
+            .await
+            {
+                seats.push(player_id);
+            }
+        }
+        assert_eq!(
+            seats,
+            [
+                PlayerId::PLAYER_1,
+                PlayerId::PLAYER_2,
+                PlayerId::PLAYER_1,
+                PlayerId::PLAYER_2
+            ]
+        );
 
-        let message_handler = tokio::spawn(async move {
-            let mut game_started_received = false;
-            let mut position_update_received = false;
-            let mut score_update_received = false;
+        // Leaving only ends the leaver's match
+        let quit = ClientMessage::Disconnect {
+            reason: DisconnectReason::Quit,
+        };
+        send(&clients[0], server_addr, 3, quit).await;
+        expect(&clients[1], |message| {
+            matches!(
+                message,
+                ServerMessage::GameEvent(GameEvent::PlayerLeft {
+                    player: PlayerId::PLAYER_1,
+                    ..
+                })
+            )
+        })
+        .await;
+        let other_match = receive_for(&clients[3], Duration::from_millis(300)).await;
+        assert!(!other_match.iter().any(|message| matches!(
+            message,
+            ServerMessage::GameEvent(GameEvent::PlayerLeft { .. })
+        )));
+
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
+    }
 
-            let start_time = std::time::Instant::now();
-            let handler_timeout = Duration::from_secs(5);
+    // One datagram filling the gap can release far more buffered reliable
+    // messages than anything queues, and every one still gets answered
+    #[tokio::test]
+    async fn test_answers_a_released_backlog() {
+        let network = MemoryNetwork::new();
+        let server =
+            GameServer::with_transport(network.bind("10.0.0.1:2906".parse().unwrap()).unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let shutdown = server.get_shutdown_handle();
+        let running = tokio::spawn(server.run());
+        let client = network.bind_any().unwrap();
+
+        // Reliable ids 1..=100 first, then the 0 they were all waiting on
+        let reliable_ids = (1..=100).chain([0]);
+        for (sequence, reliable_id) in (1..).zip(reliable_ids) {
+            let hello = ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_build: "test".to_string(),
+            };
+            let mut packet = NetworkPacket::new(sequence, 0, hello);
+            packet.set_reliable_id(Some(reliable_id));
+            let datagram = encode_datagram::<DefaultCodec, _>(&packet).unwrap();
+            client.send_to(&datagram, server_addr).await.unwrap();
+        }
 
-            while start_time.elapsed() < handler_timeout
-                && (!game_started_received || !position_update_received || !score_update_received)
-            {
-                tokio::select! {
-                    Some(message) = receive_packet_with_timeout(&client1_socket, Duration::from_millis(100)) => {
-                        match message {
-                            ServerMessage::GameStateChange(GameState::GameStarted) => {
-                                game_started_received = true;
-                                messages_received_clone.fetch_add(1, Ordering::SeqCst);
-                            }
-                            ServerMessage::PositionUpdate(_) => {
-                                position_update_received = true;
-                                messages_received_clone.fetch_add(1, Ordering::SeqCst);
-                            }
-                            ServerMessage::ScoreUpdate(_) => {
-                                score_update_received = true;
-                                messages_received_clone.fetch_add(1, Ordering::SeqCst);
-                            }
-                            _ => {}
-                        }
-                    }
-                    Some((packet, addr)) = monitor_rx.recv() => {
-                        println!("Server sent message to {}: {:?}", addr, packet.get_payload());
-                    }
-                    else => {
-                        tokio::time::sleep(Duration::from_millis(10)).await;
-                    }
+        // Unacked replies get resent, so count each one once
+        let mut welcomes = HashSet::new();
+        timeout(Duration::from_secs(5), async {
+            while welcomes.len() < 101 {
+                let mut buf = [0; RECEIVE_BUFFER_SIZE];
+                let (size, _) = client.recv_from(&mut buf).await.unwrap();
+                let packet: NetworkPacket<ServerMessage> =
+                    decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+                if let ServerMessage::Welcome { .. } = packet.get_payload() {
+                    welcomes.insert(packet.get_reliable_id());
                 }
             }
+        })
+        .await
+        .expect("server stopped answering");
 
-            (game_started_received, position_update_received, score_update_received)
-        });
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
+    }
 
-        let (game_started, position_update, score_update) = message_handler.await.unwrap();
+    #[tokio::test]
+    async fn test_inputs_with_unknown_session_are_dropped_quietly() {
+        let network = MemoryNetwork::new();
+        let server =
+            GameServer::with_transport(network.bind("10.0.0.1:2906".parse().unwrap()).unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let shutdown = server.get_shutdown_handle();
+        let running = tokio::spawn(server.run());
+
+        let client = network.bind_any().unwrap();
+        join(&client, server_addr).await;
+        for sequence in 3..13 {
+            let input = MovementData::new(SessionToken::new(0), sequence, Movement::Up);
+            send(
+                &client,
+                server_addr,
+                sequence,
+                ClientMessage::MovementInput(input),
+            )
+            .await;
+        }
 
-        assert!(
-            game_started,
-            "GameStateChange message for game start was not received"
-        );
-        assert!(
-            position_update,
-            "PositionUpdate message was not received"
-        );
-        assert!(
-            score_update,
-            "ScoreUpdate message was not received"
-        );
+        let replies = receive_for(&client, Duration::from_millis(300)).await;
+        assert!(!replies
+            .iter()
+            .any(|message| matches!(message, ServerMessage::Error(_))));
 
-        assert_eq!(
-            messages_received.load(Ordering::SeqCst),
-            3,
-            "Did not receive all expected messages"
-        );
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
+    }
+}
 
-        Ok::<_, Box<dyn std::error::Error>>(())
-    })
-    .await;
+// The same server, with the client sealing its side by hand
+#[cfg(feature = "secure")]
+mod sealed_game_server {
+    use rong_server::game_server::GameServer;
+    use rong_shared::model::{Ack, ClientMessage, NetworkPacket, ServerMessage, PROTOCOL_VERSION};
+    use rong_shared::transport::{
+        decode_datagram, encode_datagram, open_datagram, DefaultCodec, Fragmenter, Frame,
+        KeyExchange, MemoryNetwork, MemoryTransport, Opened, Role, SecureChannel, Transport,
+        RECEIVE_BUFFER_SIZE,
+    };
+    use std::net::SocketAddr;
+    use tokio::time::{timeout, Duration};
+
+    async fn send_sealed(
+        client: &MemoryTransport,
+        server: SocketAddr,
+        channel: &SecureChannel,
+        sequence: u32,
+        message: ClientMessage,
+    ) {
+        let sealed = channel
+            .seal::<DefaultCodec, _>(&NetworkPacket::new(sequence, 0, message))
+            .unwrap();
+        for datagram in Fragmenter::new().encode_body(&sealed).unwrap() {
+            client.send_to(&datagram, server).await.unwrap();
+        }
+    }
+
+    // Next message from the server that matches. Anything arriving unsealed
+    // fails the test, a repeated key exchange aside
+    async fn expect_sealed(
+        client: &MemoryTransport,
+        channel: &mut SecureChannel,
+        matches: impl Fn(&ServerMessage) -> bool,
+    ) -> ServerMessage {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let mut buf = [0; RECEIVE_BUFFER_SIZE];
+                let (size, _) = client.recv_from(&mut buf).await.unwrap();
+                let Frame::Whole(body) = open_datagram(&buf[..size]).unwrap() else {
+                    panic!("server sent a fragment");
+                };
+                match channel.open::<DefaultCodec, ServerMessage>(body).unwrap() {
+                    Opened::Packet(packet) if matches(packet.get_payload()) => {
+                        return packet.get_payload().clone()
+                    }
+                    Opened::Packet(_) | Opened::KeyExchange(_) => {}
+                }
+            }
+        })
+        .await
+        .expect("server never sent the expected message")
+    }
 
-    match result {
-        Ok(Ok(_)) => println!("Test completed successfully"),
-        Ok(Err(e)) => panic!("Test failed: {:?}", e),
-        Err(_) => panic!("Test timed out after {:?}", test_timeout),
+    #[tokio::test]
+    async fn test_joins_over_a_sealed_link() {
+        let network = MemoryNetwork::new();
+        let server =
+            GameServer::with_transport(network.bind("10.0.0.1:2906".parse().unwrap()).unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let shutdown = server.get_shutdown_handle();
+        let running = tokio::spawn(server.run());
+        let client = network.bind_any().unwrap();
+
+        // Hello and the key exchange are the only unsealed packets
+        let hello = ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+        };
+        let exchange = KeyExchange::new();
+        let key_exchange = ClientMessage::KeyExchange(exchange.public_key());
+        for (sequence, message) in [(1, hello), (2, key_exchange)] {
+            let datagram =
+                encode_datagram::<DefaultCodec, _>(&NetworkPacket::new(sequence, 0, message))
+                    .unwrap();
+            client.send_to(&datagram, server_addr).await.unwrap();
+        }
+        let server_key = timeout(Duration::from_secs(5), async {
+            loop {
+                let mut buf = [0; RECEIVE_BUFFER_SIZE];
+                let (size, _) = client.recv_from(&mut buf).await.unwrap();
+                let packet: NetworkPacket<ServerMessage> =
+                    decode_datagram::<DefaultCodec, _>(&buf[..size]).unwrap();
+                if let ServerMessage::KeyExchange(key) = packet.get_payload() {
+                    return *key;
+                }
+            }
+        })
+        .await
+        .expect("server never answered the key exchange");
+        let mut channel = exchange.finish(Role::Client, server_key).unwrap();
+
+        send_sealed(&client, server_addr, &channel, 3, ClientMessage::JoinQueue).await;
+        let ack = expect_sealed(&client, &mut channel, |message| {
+            matches!(message, ServerMessage::Success(_))
+        })
+        .await;
+        assert!(matches!(ack, ServerMessage::Success(Ack::AddedToQueue(_))));
+
+        // Pings are answered sealed too
+        send_sealed(&client, server_addr, &channel, 4, ClientMessage::Ping).await;
+        expect_sealed(&client, &mut channel, |message| {
+            matches!(message, ServerMessage::Pong(_))
+        })
+        .await;
+
+        shutdown.shutdown();
+        running.await.unwrap().unwrap();
     }
 }
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rong_shared::model::{NetworkPacket, ServerMessage};
+use rong_shared::transport::{decode_datagram, write_header, DefaultCodec, WireCodec};
+
+fuzz_target!(|data: &[u8]| {
+    // Raw datagrams, which mostly exercise the size and header checks
+    let _ = decode_datagram::<DefaultCodec, NetworkPacket<ServerMessage>>(data);
+
+    // Bodies behind a valid header, so the codec itself sees every input
+    let _ = decode_datagram::<DefaultCodec, NetworkPacket<ServerMessage>>(&write_header(data));
+
+    let _ = DefaultCodec::decode::<NetworkPacket<ServerMessage>>(data);
+});
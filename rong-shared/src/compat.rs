@@ -0,0 +1,340 @@
+use crate::error::{CompatError, ServerError};
+use crate::model::{
+    Ack, ClientMessage, DisconnectReason, GameEvent, GameSnapshot, GameStatus, GameUpdateData,
+    GameUpdateDelta, Movement, MovementData, NetworkPacket, PlayerId, PositionData, Score,
+    ScoreData, ServerMessage, SessionToken, Wall, BASE_PROTOCOL_VERSION,
+};
+use crate::transport::{BincodeCodec, WireCodec};
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+    Golden wire fixtures.
+
+    Every message variant has a canonical sample below, and its bincode
+    encoding is checked in under fixtures/wire/v<BASE_PROTOCOL_VERSION>.
+    check_fixtures decodes those files with the current model, so a layout
+    change that would make deployed clients misread a message shows up as
+    an error instead of in production.
+
+    Changing the layout on purpose means bumping BASE_PROTOCOL_VERSION and
+    blessing a new set of fixtures, the old set stays as a record.
+*/
+
+const FIXTURE_EXTENSION: &str = "bin";
+
+enum Sample {
+    Client(ClientMessage),
+    Server(ServerMessage),
+    ClientPacket(NetworkPacket<ClientMessage>),
+    ServerPacket(NetworkPacket<ServerMessage>),
+}
+
+impl Sample {
+    fn encode(&self) -> Vec<u8> {
+        let encoded = match self {
+            Sample::Client(message) => BincodeCodec::encode(message),
+            Sample::Server(message) => BincodeCodec::encode(message),
+            Sample::ClientPacket(packet) => BincodeCodec::encode(packet),
+            Sample::ServerPacket(packet) => BincodeCodec::encode(packet),
+        };
+        encoded.expect("Fixture samples always encode")
+    }
+
+    fn check(&self, name: &str, bytes: &[u8]) -> Result<(), CompatError> {
+        match self {
+            Sample::Client(message) => check_fixture(name, bytes, message),
+            Sample::Server(message) => check_fixture(name, bytes, message),
+            Sample::ClientPacket(packet) => check_fixture(name, bytes, packet),
+            Sample::ServerPacket(packet) => check_fixture(name, bytes, packet),
+        }
+    }
+}
+
+// Where the fixtures for the current protocol version live under root
+pub fn fixture_dir(root: &Path) -> PathBuf {
+    root.join(format!("v{}", BASE_PROTOCOL_VERSION))
+}
+
+// Decodes one fixture with the current model and compares it against the sample
+pub fn check_fixture<T>(name: &str, bytes: &[u8], sample: &T) -> Result<(), CompatError>
+where
+    T: Serialize + DeserializeOwned + Debug,
+{
+    let decoded: T = BincodeCodec::decode(bytes).map_err(|e| CompatError::Undecodable {
+        name: name.to_string(),
+        reason: e.to_string(),
+    })?;
+
+    // Messages don't implement PartialEq, but Debug shows every field
+    let decoded = format!("{:?}", decoded);
+    let expected = format!("{:?}", sample);
+    if decoded != expected {
+        return Err(CompatError::Misread {
+            name: name.to_string(),
+            decoded,
+            expected,
+        });
+    }
+
+    let encoded = BincodeCodec::encode(sample).map_err(|e| CompatError::Undecodable {
+        name: name.to_string(),
+        reason: e.to_string(),
+    })?;
+    if encoded != bytes {
+        return Err(CompatError::LayoutChanged(name.to_string()));
+    }
+    Ok(())
+}
+
+// Every problem found in dir, empty when the current model still matches
+pub fn check_fixtures(dir: &Path) -> Vec<CompatError> {
+    let samples = samples();
+    let mut errors = Vec::new();
+
+    for (name, sample) in &samples {
+        match fs::read(fixture_path(dir, name)) {
+            Ok(bytes) => errors.extend(sample.check(name, &bytes).err()),
+            Err(_) => errors.push(CompatError::MissingFixture(name.clone())),
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !samples.iter().any(|(sample, _)| sample == name) {
+                errors.push(CompatError::Orphaned(name.to_string()));
+            }
+        }
+    }
+    errors
+}
+
+// Writes the current encoding of every sample into dir
+pub fn bless_fixtures(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (name, sample) in samples() {
+        fs::write(fixture_path(dir, &name), sample.encode())?;
+    }
+    Ok(())
+}
+
+fn fixture_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name).with_extension(FIXTURE_EXTENSION)
+}
+
+fn samples() -> Vec<(String, Sample)> {
+    let session = SessionToken::new(0x0102_0304_0506_0708);
+    let baseline = GameUpdateData::new(
+        PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5)),
+        ScoreData::new(Score::new(0), Score::new(0)),
+        GameStatus::GameStarted,
+    );
+    let current = GameUpdateData::new(
+        PositionData::new((0.25, 0.1), (0.5, 0.9), (0.75, 0.5)),
+        ScoreData::new(Score::new(1), Score::new(0)),
+        GameStatus::GameStarted,
+    )
+    .with_last_inputs(vec![(PlayerId::PLAYER_1, 42)]);
+
+    let client = vec![
+        ClientMessage::Hello {
+            protocol_version: BASE_PROTOCOL_VERSION,
+            client_build: "fixture".to_string(),
+        },
+        ClientMessage::JoinQueue,
+        ClientMessage::LeaveQueue(session),
+        ClientMessage::MovementInput(MovementData::new(session, 42, Movement::Up)),
+        ClientMessage::SnapshotAck(17),
+        ClientMessage::KeyExchange([7; 32]),
+        ClientMessage::Ping,
+        ClientMessage::Pong(1_700_000_000_000),
+        ClientMessage::KeepAlive,
+        ClientMessage::Disconnect {
+            reason: DisconnectReason::Quit,
+        },
+    ];
+
+    let mut server = vec![
+        ServerMessage::Welcome {
+            protocol_version: BASE_PROTOCOL_VERSION,
+        },
+        ServerMessage::GameFound(PlayerId::PLAYER_2),
+        ServerMessage::GameUpdate(GameSnapshot::Full {
+            id: 3,
+            data: current.clone(),
+        }),
+        ServerMessage::GameUpdate(GameSnapshot::Delta(GameUpdateDelta::between(
+            4, 3, &baseline, &current,
+        ))),
+        ServerMessage::Success(Ack::AddedToQueue(session)),
+        ServerMessage::Success(Ack::RemovedFromQueue),
+        ServerMessage::KeyExchange([9; 32]),
+        ServerMessage::Ping,
+        ServerMessage::Pong(1_700_000_000_000),
+        ServerMessage::GameEvent(GameEvent::PaddleHit(PlayerId::PLAYER_1)),
+        ServerMessage::GameEvent(GameEvent::WallBounce(Wall::Top)),
+        ServerMessage::GameEvent(GameEvent::PointScored(PlayerId::PLAYER_2)),
+        ServerMessage::GameEvent(GameEvent::ServeStarted(PlayerId::PLAYER_1)),
+        ServerMessage::GameEvent(GameEvent::MatchPoint(PlayerId::PLAYER_2)),
+        ServerMessage::GameEvent(GameEvent::PlayerLeft {
+            player: PlayerId::PLAYER_1,
+            reason: DisconnectReason::TimedOut,
+        }),
+        ServerMessage::KeepAlive,
+        ServerMessage::Disconnect {
+            reason: DisconnectReason::ServerShutdown,
+        },
+    ];
+    server.extend(
+        [
+            ServerError::Io("io".to_string()),
+            ServerError::Utf8("utf8".to_string()),
+            ServerError::PlayerNotFound,
+            ServerError::GameFull,
+            ServerError::GameStateUpdateError,
+            ServerError::VersionMismatch {
+                server: BASE_PROTOCOL_VERSION,
+                client: 1,
+            },
+            ServerError::HandshakeRequired,
+            ServerError::InvalidSession,
+            ServerError::QueueFull,
+            ServerError::RateLimited {
+                retry_after_ms: 500,
+            },
+            ServerError::Banned,
+            ServerError::InvalidState(GameStatus::GameOver),
+            ServerError::MatchNotFound,
+        ]
+        .map(ServerMessage::Error),
+    );
+
+    let mut samples: Vec<(String, Sample)> = client
+        .into_iter()
+        .map(|message| (client_name(&message), Sample::Client(message)))
+        .chain(
+            server
+                .into_iter()
+                .map(|message| (server_name(&message), Sample::Server(message))),
+        )
+        .collect();
+    samples.push((
+        "client_packet".to_string(),
+        Sample::ClientPacket(NetworkPacket::new(7, 1_234, ClientMessage::JoinQueue)),
+    ));
+    samples.push((
+        "server_packet".to_string(),
+        Sample::ServerPacket(NetworkPacket::new(8, 5_678, ServerMessage::Ping)),
+    ));
+    samples
+}
+
+// No wildcards, so a new variant doesn't compile until it has a fixture name
+// (and should get a sample above)
+fn client_name(message: &ClientMessage) -> String {
+    let name = match message {
+        ClientMessage::Hello { .. } => "hello",
+        ClientMessage::JoinQueue => "join_queue",
+        ClientMessage::LeaveQueue(_) => "leave_queue",
+        ClientMessage::MovementInput(_) => "movement_input",
+        ClientMessage::SnapshotAck(_) => "snapshot_ack",
+        ClientMessage::KeyExchange(_) => "key_exchange",
+        ClientMessage::Ping => "ping",
+        ClientMessage::Pong(_) => "pong",
+        ClientMessage::KeepAlive => "keep_alive",
+        ClientMessage::Disconnect { .. } => "disconnect",
+    };
+    format!("client_{}", name)
+}
+
+fn server_name(message: &ServerMessage) -> String {
+    let name = match message {
+        ServerMessage::Welcome { .. } => "welcome".to_string(),
+        ServerMessage::GameFound(_) => "game_found".to_string(),
+        ServerMessage::GameUpdate(GameSnapshot::Full { .. }) => "game_update_full".to_string(),
+        ServerMessage::GameUpdate(GameSnapshot::Delta(_)) => "game_update_delta".to_string(),
+        ServerMessage::Success(Ack::AddedToQueue(_)) => "added_to_queue".to_string(),
+        ServerMessage::Success(Ack::RemovedFromQueue) => "removed_from_queue".to_string(),
+        // Codes are stable, so they make stable names too
+        ServerMessage::Error(err) => format!("error_{}", err.code()),
+        ServerMessage::KeyExchange(_) => "key_exchange".to_string(),
+        ServerMessage::Ping => "ping".to_string(),
+        ServerMessage::Pong(_) => "pong".to_string(),
+        ServerMessage::KeepAlive => "keep_alive".to_string(),
+        ServerMessage::Disconnect { .. } => "disconnect".to_string(),
+        ServerMessage::GameEvent(event) => match event {
+            GameEvent::PaddleHit(_) => "event_paddle_hit",
+            GameEvent::WallBounce(_) => "event_wall_bounce",
+            GameEvent::PointScored(_) => "event_point_scored",
+            GameEvent::ServeStarted(_) => "event_serve_started",
+            GameEvent::MatchPoint(_) => "event_match_point",
+            GameEvent::PlayerLeft { .. } => "event_player_left",
+        }
+        .to_string(),
+    };
+    format!("server_{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Quantized positions have their own layout, the fixtures are for the default build
+    #[cfg(not(feature = "quantized-positions"))]
+    #[test]
+    fn test_fixtures_match_current_layout() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/wire");
+        let dir = fixture_dir(&root);
+        if std::env::var_os("RONG_BLESS_FIXTURES").is_some() {
+            bless_fixtures(&dir).unwrap();
+        }
+
+        let errors = check_fixtures(&dir);
+        assert!(
+            errors.is_empty(),
+            "Wire layout no longer matches the v{} fixtures. Restore the layout, or bump \
+             BASE_PROTOCOL_VERSION and rerun with RONG_BLESS_FIXTURES=1:\n{:#?}",
+            BASE_PROTOCOL_VERSION,
+            errors
+        );
+    }
+
+    #[test]
+    fn test_sample_names_are_unique() {
+        let samples = samples();
+        for (i, (name, _)) in samples.iter().enumerate() {
+            assert!(
+                samples[i + 1..].iter().all(|(other, _)| other != name),
+                "Duplicate fixture name {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_shifted_variant_is_flagged() {
+        // What an old client sending Ping looks like after a variant was
+        // inserted before it
+        let bytes = BincodeCodec::encode(&ClientMessage::Ping).unwrap();
+        let shifted = ClientMessage::Pong(0);
+        assert!(matches!(
+            check_fixture("client_ping", &bytes, &shifted),
+            Err(CompatError::Misread { .. }) | Err(CompatError::Undecodable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_fixtures_are_reported() {
+        let errors = check_fixtures(Path::new("does/not/exist"));
+        assert_eq!(errors.len(), samples().len());
+        assert!(matches!(errors[0], CompatError::MissingFixture(_)));
+    }
+}
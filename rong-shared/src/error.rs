@@ -1,3 +1,5 @@
+use crate::model::GameStatus;
+
 use bincode;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -8,11 +10,22 @@ pub enum GameError {
     Io(String), // Changed from std::io::Error to String for serializability
     #[error("UTF-8 error: {0}")]
     Utf8(String), // Changed from std::str::Utf8Error to String
-                  // Uncomment if needed in the future
-                  // #[error("Game error: {0}")]
-                  // Game(String),
+    #[error("Need {required} players, have {present}")]
+    NotEnoughPlayers { required: usize, present: usize },
+    #[error("Not allowed while the game is {0:?}")]
+    InvalidState(GameStatus),
+    #[error(transparent)]
+    Server(#[from] ServerError),
 }
 
+/*
+    Everything the server can report in ServerMessage::Error.
+
+    Each variant has a numeric code, see ServerError::code, that keeps its
+    meaning across releases so clients can branch on it instead of on the
+    message text. New variants go at the end with a fresh code, retired
+    codes are never handed out again.
+*/
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
 pub enum ServerError {
     #[error("IO error: {0}")]
@@ -25,6 +38,71 @@ pub enum ServerError {
     GameFull,
     #[error("Game State Update Error")]
     GameStateUpdateError,
+    #[error("Protocol version mismatch: server is on {server}, client is on {client}")]
+    VersionMismatch { server: u32, client: u32 },
+    #[error("Handshake required")]
+    HandshakeRequired,
+    #[error("Unknown session")]
+    InvalidSession,
+    #[error("Matchmaking queue is full")]
+    QueueFull,
+    // Reserved, nothing returns it yet
+    #[error("Too many requests, retry in {retry_after_ms}ms")]
+    RateLimited { retry_after_ms: u32 },
+    // Reserved, nothing returns it yet
+    #[error("Banned from this server")]
+    Banned,
+    #[error("Not allowed while the game is {0:?}")]
+    InvalidState(GameStatus),
+    #[error("Match not found")]
+    MatchNotFound,
+}
+
+impl ServerError {
+    // 1xx handshake and session, 2xx admission, 3xx gameplay, 9xx internal
+    pub fn code(&self) -> u16 {
+        match self {
+            ServerError::VersionMismatch { .. } => 100,
+            ServerError::HandshakeRequired => 101,
+            ServerError::InvalidSession => 102,
+            ServerError::QueueFull => 200,
+            ServerError::GameFull => 201,
+            ServerError::RateLimited { .. } => 202,
+            ServerError::Banned => 203,
+            ServerError::PlayerNotFound => 300,
+            ServerError::MatchNotFound => 301,
+            ServerError::InvalidState(_) => 302,
+            ServerError::GameStateUpdateError => 303,
+            ServerError::Io(_) => 900,
+            ServerError::Utf8(_) => 901,
+        }
+    }
+
+    // The client may succeed by simply trying again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ServerError::QueueFull
+                | ServerError::GameFull
+                | ServerError::RateLimited { .. }
+                | ServerError::Io(_)
+        )
+    }
+}
+
+// How game logic failures are reported to the client that caused them
+impl From<GameError> for ServerError {
+    fn from(err: GameError) -> Self {
+        match err {
+            GameError::Io(message) => ServerError::Io(message),
+            GameError::Utf8(message) => ServerError::Utf8(message),
+            GameError::NotEnoughPlayers { .. } => {
+                ServerError::InvalidState(GameStatus::WaitingForPlayers)
+            }
+            GameError::InvalidState(status) => ServerError::InvalidState(status),
+            GameError::Server(err) => err,
+        }
+    }
 }
 
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
@@ -35,6 +113,8 @@ pub enum ClientError {
     Utf8(String),
     #[error("Serialization error: {0}")]
     Serialization(String),
+    #[error("Server error: {0}")]
+    Server(ServerError),
 }
 
 impl From<std::io::Error> for ClientError {
@@ -55,6 +135,92 @@ impl From<bincode::Error> for ClientError {
     }
 }
 
+#[derive(Error, Debug, Clone)]
+pub enum CodecError {
+    #[error("Failed to encode packet: {0}")]
+    Encode(String),
+    #[error("Failed to decode packet: {0}")]
+    Decode(#[from] DecodeError),
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum DecodeError {
+    #[error("Packet of {size} bytes exceeds the {limit} byte limit")]
+    TooLarge { size: usize, limit: usize },
+    #[error("Packet ended unexpectedly")]
+    Truncated,
+    #[error("Malformed packet: {0}")]
+    Malformed(String),
+    #[error("Bad header: {0}")]
+    Header(#[from] HeaderError),
+    #[error("Packet failed authentication")]
+    Unauthenticated,
+    #[error("Replayed packet {0}")]
+    Replayed(u32),
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum HeaderError {
+    #[error("Datagram too short for a header: {0} bytes")]
+    Truncated(usize),
+    #[error("Bad magic bytes")]
+    BadMagic,
+    #[error("Unknown protocol id: {0}")]
+    UnknownProtocol(u16),
+    #[error("Checksum mismatch")]
+    BadChecksum,
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum CompatError {
+    #[error("No fixture for {0}, bless the fixtures")]
+    MissingFixture(String),
+    #[error("Fixture {name} no longer decodes: {reason}")]
+    Undecodable { name: String, reason: String },
+    #[error("Fixture {name} decodes as {decoded}, expected {expected}")]
+    Misread {
+        name: String,
+        decoded: String,
+        expected: String,
+    },
+    #[error("{0} now encodes differently from its fixture")]
+    LayoutChanged(String),
+    #[error("Fixture {0} has no sample, was the message removed?")]
+    Orphaned(String),
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ConditionsError {
+    #[error("{0} needs a value")]
+    MissingValue(String),
+    #[error("Invalid value {value:?} for {flag}")]
+    InvalidValue { flag: String, value: String },
+}
+
+impl From<CodecError> for ClientError {
+    fn from(err: CodecError) -> Self {
+        ClientError::Serialization(err.to_string())
+    }
+}
+
+impl From<DecodeError> for ClientError {
+    fn from(err: DecodeError) -> Self {
+        ClientError::Serialization(err.to_string())
+    }
+}
+
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl From<CodecError> for std::io::Error {
+    fn from(err: CodecError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, GameError>;
 
 // Helper functions to convert from std errors to our serializable errors
@@ -81,3 +247,35 @@ impl From<std::str::Utf8Error> for ServerError {
         ServerError::Utf8(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_stable() {
+        // Clients depend on these, changing one is a breaking change
+        assert_eq!(ServerError::HandshakeRequired.code(), 101);
+        assert_eq!(ServerError::QueueFull.code(), 200);
+        assert_eq!(ServerError::RateLimited { retry_after_ms: 0 }.code(), 202);
+        assert_eq!(ServerError::Banned.code(), 203);
+        assert_eq!(ServerError::MatchNotFound.code(), 301);
+        assert_eq!(ServerError::InvalidState(GameStatus::GameOver).code(), 302);
+    }
+
+    #[test]
+    fn test_game_error_mapping() {
+        let err: ServerError = GameError::NotEnoughPlayers {
+            required: 2,
+            present: 1,
+        }
+        .into();
+        assert!(matches!(
+            err,
+            ServerError::InvalidState(GameStatus::WaitingForPlayers)
+        ));
+
+        let err: ServerError = GameError::Server(ServerError::PlayerNotFound).into();
+        assert_eq!(err.code(), 300);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod compat;
 pub mod error;
 pub mod model;
+pub mod transport;
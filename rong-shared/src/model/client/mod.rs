@@ -1,20 +1,66 @@
 use serde::{Deserialize, Serialize};
 
+use super::shared::DisconnectReason;
 use super::shared::Movement;
 use super::shared::NetworkPacket;
-use super::shared::PlayerId;
+use super::shared::PublicKey;
+use super::shared::SessionToken;
 
 pub type ClientPacket = NetworkPacket<ClientMessage>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ClientMessage {
+    // Must stay the first variant so any build can decode it
+    Hello {
+        protocol_version: u32,
+        client_build: String,
+    },
     JoinQueue,
-    LeaveQueue,
+    LeaveQueue(SessionToken),
     MovementInput(MovementData),
+    // Latest GameUpdate snapshot applied, used as the server's delta baseline
+    SnapshotAck(u32),
+    // Sent unsealed after Welcome when the secure feature is on
+    KeyExchange(PublicKey),
+    // Clock probes, both sides ping. A Pong echoes the Ping's packet timestamp
+    // and carries the responder's clock in its own
+    Ping,
+    Pong(u64),
+    // Sent when nothing else has been for a while, so the server doesn't time us out
+    KeepAlive,
+    // Leaving for good, the server frees our seat straight away
+    Disconnect {
+        reason: DisconnectReason,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MovementData {
-    player_id: PlayerId,
+    // The server works out which paddle moves from this and the sender's address
+    session: SessionToken,
+    // Counts up from zero per client, echoed in GameUpdateData once applied
+    input_id: u32,
     movement: Movement,
 }
+
+impl MovementData {
+    pub fn new(session: SessionToken, input_id: u32, movement: Movement) -> Self {
+        MovementData {
+            session,
+            input_id,
+            movement,
+        }
+    }
+
+    pub fn get_session(&self) -> SessionToken {
+        self.session
+    }
+
+    pub fn get_input_id(&self) -> u32 {
+        self.input_id
+    }
+
+    pub fn get_movement(&self) -> &Movement {
+        &self.movement
+    }
+}
@@ -1,3 +1,7 @@
 mod client;
 mod server;
 mod shared;
+
+pub use client::*;
+pub use server::*;
+pub use shared::*;
@@ -1,4 +1,6 @@
-use super::shared::{GameUpdateData, NetworkPacket, PlayerId};
+use super::shared::{
+    DisconnectReason, GameSnapshot, NetworkPacket, PlayerId, PublicKey, SessionToken,
+};
 use crate::error::ServerError;
 
 use serde::{Deserialize, Serialize};
@@ -7,14 +9,60 @@ pub type ServerPacket = NetworkPacket<ServerMessage>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerMessage {
+    // Must stay the first variant so any build can decode it
+    Welcome { protocol_version: u32 },
     GameFound(PlayerId),
-    GameUpdate(GameUpdateData),
+    GameUpdate(GameSnapshot),
     Success(Ack),
     Error(ServerError),
+    // Reply to ClientMessage::KeyExchange, the last unsealed message
+    KeyExchange(PublicKey),
+    // Same as ClientMessage::Ping and Pong
+    Ping,
+    Pong(u64),
+    // Something happened on the field, clients play effects off these
+    GameEvent(GameEvent),
+    // Same as ClientMessage::KeepAlive
+    KeepAlive,
+    // The server dropped this client, nothing more will be sent
+    Disconnect { reason: DisconnectReason },
+}
+
+// Every failure reaches the client the same way
+impl From<ServerError> for ServerMessage {
+    fn from(err: ServerError) -> Self {
+        ServerMessage::Error(err)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GameEvent {
+    PaddleHit(PlayerId),
+    WallBounce(Wall),
+    // New totals follow in the next GameUpdate
+    PointScored(PlayerId),
+    // The player the ball is heading towards
+    ServeStarted(PlayerId),
+    // The player one point away from winning
+    MatchPoint(PlayerId),
+    // Sent to the players left behind, the match is over
+    PlayerLeft {
+        player: PlayerId,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Wall {
+    Top,
+    Bottom,
+    Left,
+    Right,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Ack {
-    AddedToQueue,
+    // Carries the token the client must attach to everything it sends from now on
+    AddedToQueue(SessionToken),
     RemovedFromQueue,
 }
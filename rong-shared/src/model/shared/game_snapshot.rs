@@ -0,0 +1,164 @@
+use super::{
+    Entity, EntityId, GameStatus, GameUpdateData, PlayerId, PositionData, Score, ScoreData,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Debug;
+
+/*  A GameUpdate on the wire, either complete or relative to an acked baseline */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GameSnapshot {
+    Full { id: u32, data: GameUpdateData },
+    Delta(GameUpdateDelta),
+}
+
+impl GameSnapshot {
+    pub fn get_id(&self) -> u32 {
+        match self {
+            GameSnapshot::Full { id, .. } => *id,
+            GameSnapshot::Delta(delta) => delta.id,
+        }
+    }
+}
+
+// Only the entities, scores and inputs that differ from the baseline are sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameUpdateDelta {
+    id: u32,
+    baseline_id: u32,
+    positions: Option<KeyedDelta<Entity>>,
+    scores: Option<KeyedDelta<(PlayerId, Score)>>,
+    game_status: Option<GameStatus>,
+    last_inputs: Option<KeyedDelta<(PlayerId, u32)>>,
+}
+
+impl GameUpdateDelta {
+    pub fn between(
+        id: u32,
+        baseline_id: u32,
+        baseline: &GameUpdateData,
+        current: &GameUpdateData,
+    ) -> Self {
+        GameUpdateDelta {
+            id,
+            baseline_id,
+            positions: KeyedDelta::between(
+                baseline.get_positions().get_entities(),
+                current.get_positions().get_entities(),
+            ),
+            scores: KeyedDelta::between(
+                baseline.get_scores().get_scores(),
+                current.get_scores().get_scores(),
+            ),
+            game_status: changed(&baseline.get_game_status(), &current.get_game_status()),
+            last_inputs: KeyedDelta::between(baseline.get_last_inputs(), current.get_last_inputs()),
+        }
+    }
+
+    pub fn get_baseline_id(&self) -> u32 {
+        self.baseline_id
+    }
+
+    pub fn apply(&self, baseline: &GameUpdateData) -> GameUpdateData {
+        let positions = match &self.positions {
+            Some(delta) => {
+                PositionData::from_entities(delta.apply(baseline.get_positions().get_entities()))
+            }
+            None => baseline.get_positions().clone(),
+        };
+        let scores = match &self.scores {
+            Some(delta) => ScoreData::from_scores(delta.apply(baseline.get_scores().get_scores())),
+            None => baseline.get_scores().clone(),
+        };
+        let last_inputs = match &self.last_inputs {
+            Some(delta) => delta.apply(baseline.get_last_inputs()),
+            None => baseline.get_last_inputs().to_vec(),
+        };
+
+        GameUpdateData::new(
+            positions,
+            scores,
+            self.game_status.unwrap_or(baseline.get_game_status()),
+        )
+        .with_last_inputs(last_inputs)
+    }
+}
+
+// An entry a delta can match up with the same entry in the baseline
+trait Keyed: PartialEq + Clone {
+    type Key: PartialEq + Clone + Debug + Serialize + DeserializeOwned;
+
+    fn key(&self) -> Self::Key;
+}
+
+impl Keyed for Entity {
+    type Key = EntityId;
+
+    fn key(&self) -> EntityId {
+        self.get_id()
+    }
+}
+
+impl<K, V> Keyed for (K, V)
+where
+    K: PartialEq + Clone + Debug + Serialize + DeserializeOwned,
+    V: PartialEq + Clone,
+{
+    type Key = K;
+
+    fn key(&self) -> K {
+        self.0.clone()
+    }
+}
+
+/*  Changes to a list of keyed entries: the ones that are new or differ, and the keys that are gone */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KeyedDelta<T: Keyed> {
+    changed: Vec<T>,
+    removed: Vec<T::Key>,
+}
+
+impl<T: Keyed> KeyedDelta<T> {
+    // None when the lists are the same
+    fn between(baseline: &[T], current: &[T]) -> Option<Self> {
+        let changed: Vec<T> = current
+            .iter()
+            .filter(|entry| !baseline.contains(entry))
+            .cloned()
+            .collect();
+        let removed: Vec<T::Key> = baseline
+            .iter()
+            .map(Keyed::key)
+            .filter(|key| !current.iter().any(|entry| entry.key() == *key))
+            .collect();
+
+        if changed.is_empty() && removed.is_empty() {
+            None
+        } else {
+            Some(KeyedDelta { changed, removed })
+        }
+    }
+
+    fn apply(&self, baseline: &[T]) -> Vec<T> {
+        let mut entries: Vec<T> = baseline
+            .iter()
+            .filter(|entry| !self.removed.contains(&entry.key()))
+            .cloned()
+            .collect();
+        for entry in &self.changed {
+            match entries.iter_mut().find(|other| other.key() == entry.key()) {
+                Some(other) => *other = entry.clone(),
+                None => entries.push(entry.clone()),
+            }
+        }
+        entries
+    }
+}
+
+fn changed<T: PartialEq + Clone>(baseline: &T, current: &T) -> Option<T> {
+    if baseline == current {
+        None
+    } else {
+        Some(current.clone())
+    }
+}
@@ -1,12 +1,14 @@
-use super::{GameStatus, PositionData, ScoreData};
+use super::{GameStatus, PlayerId, PositionData, ScoreData};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GameUpdateData {
     positions: PositionData,
     scores: ScoreData,
     game_status: GameStatus,
+    // Newest MovementInput applied for each player, so clients can replay the rest
+    last_inputs: Vec<(PlayerId, u32)>,
 }
 
 impl GameUpdateData {
@@ -19,6 +21,41 @@ impl GameUpdateData {
             positions: position_data,
             scores: score_data,
             game_status,
+            last_inputs: Vec::new(),
         }
     }
+
+    pub fn with_last_inputs(mut self, last_inputs: Vec<(PlayerId, u32)>) -> Self {
+        self.last_inputs = last_inputs;
+        self
+    }
+
+    pub fn with_positions(mut self, positions: PositionData) -> Self {
+        self.positions = positions;
+        self
+    }
+
+    pub fn get_positions(&self) -> &PositionData {
+        &self.positions
+    }
+
+    pub fn get_scores(&self) -> &ScoreData {
+        &self.scores
+    }
+
+    pub fn get_game_status(&self) -> GameStatus {
+        self.game_status
+    }
+
+    pub fn get_last_inputs(&self) -> &[(PlayerId, u32)] {
+        &self.last_inputs
+    }
+
+    // None until the server has applied an input from that player
+    pub fn get_last_input(&self, player_id: PlayerId) -> Option<u32> {
+        self.last_inputs
+            .iter()
+            .find(|(id, _)| *id == player_id)
+            .map(|(_, input_id)| *input_id)
+    }
 }
@@ -5,6 +5,11 @@ use serde::{Deserialize, Serialize};
 pub struct NetworkPacket<T> {
     sequence: u32,
     timestamp: u64,
+    // Latest remote sequence seen, plus a bitfield of the 32 before it
+    ack: u32,
+    ack_bits: u32,
+    // Set on reliable messages, used for ordering and duplicate suppression
+    reliable_id: Option<u32>,
     payload: T,
 }
 
@@ -13,6 +18,9 @@ impl<T> NetworkPacket<T> {
         NetworkPacket {
             sequence,
             timestamp,
+            ack: 0,
+            ack_bits: 0,
+            reliable_id: None,
             payload,
         }
     }
@@ -25,10 +33,22 @@ impl<T> NetworkPacket<T> {
         self.timestamp
     }
 
+    pub fn get_ack(&self) -> (u32, u32) {
+        (self.ack, self.ack_bits)
+    }
+
+    pub fn get_reliable_id(&self) -> Option<u32> {
+        self.reliable_id
+    }
+
     pub fn get_payload(&self) -> &T {
         &self.payload
     }
 
+    pub fn into_payload(self) -> T {
+        self.payload
+    }
+
     pub fn set_sequence(&mut self, sequence: u32) {
         self.sequence = sequence;
     }
@@ -37,6 +57,15 @@ impl<T> NetworkPacket<T> {
         self.timestamp = timestamp;
     }
 
+    pub fn set_ack(&mut self, ack: u32, ack_bits: u32) {
+        self.ack = ack;
+        self.ack_bits = ack_bits;
+    }
+
+    pub fn set_reliable_id(&mut self, reliable_id: Option<u32>) {
+        self.reliable_id = reliable_id;
+    }
+
     pub fn set_payload(&mut self, payload: T) {
         self.payload = payload;
     }
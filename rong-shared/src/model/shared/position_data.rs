@@ -1,47 +1,179 @@
-use super::{EntityId, PlayerId, Position};
+use super::{quantize_position, EntityId, PlayerId, Position};
 
 use serde::{Deserialize, Serialize};
 use std::ops::{Index, IndexMut};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Entity {
+    id: EntityId,
+    #[cfg_attr(
+        feature = "quantized-positions",
+        serde(with = "super::quantized_position::wire")
+    )]
+    position: Position,
+}
+
+impl Entity {
+    pub fn new(id: EntityId, position: Position) -> Self {
+        Entity { id, position }
+    }
+
+    pub fn get_id(&self) -> EntityId {
+        self.id
+    }
+
+    pub fn get_position(&self) -> Position {
+        self.position
+    }
+}
+
+/*  Where everything on the field is, one entry per entity in no particular order */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PositionData {
-    player1_position: Position,
-    player2_position: Position,
-    ball_position: Position,
+    entities: Vec<Entity>,
 }
 
 impl PositionData {
+    // The classic layout, two paddles and a ball
     pub fn new(
         player1_position: Position,
         player2_position: Position,
         ball_position: Position,
     ) -> Self {
+        PositionData::from_entities(vec![
+            Entity::new(EntityId::Player(PlayerId::PLAYER_1), player1_position),
+            Entity::new(EntityId::Player(PlayerId::PLAYER_2), player2_position),
+            Entity::new(EntityId::Ball, ball_position),
+        ])
+    }
+
+    pub fn from_entities(entities: Vec<Entity>) -> Self {
+        PositionData { entities }
+    }
+
+    pub fn get_entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub fn get(&self, entity_id: EntityId) -> Option<Position> {
+        self.entities
+            .iter()
+            .find(|entity| entity.id == entity_id)
+            .map(|entity| entity.position)
+    }
+
+    // Moves the entity, adding it if it isn't on the field yet
+    pub fn set(&mut self, entity_id: EntityId, position: Position) {
+        match self
+            .entities
+            .iter_mut()
+            .find(|entity| entity.id == entity_id)
+        {
+            Some(entity) => entity.position = position,
+            None => self.entities.push(Entity::new(entity_id, position)),
+        }
+    }
+
+    // Player 1, player 2 and ball, for code written against the classic layout.
+    // None if any of the three is missing
+    pub fn get_classic(&self) -> Option<(Position, Position, Position)> {
+        Some((
+            self.get(EntityId::Player(PlayerId::PLAYER_1))?,
+            self.get(EntityId::Player(PlayerId::PLAYER_2))?,
+            self.get(EntityId::Ball)?,
+        ))
+    }
+
+    // True if both hold the same entities and none of them is more than
+    // tolerance away from where it is in other, on either axis
+    pub fn is_within(&self, other: &PositionData, tolerance: f32) -> bool {
+        self.entities.len() == other.entities.len()
+            && self.entities.iter().all(|entity| {
+                other.get(entity.id).is_some_and(|(x, y)| {
+                    (entity.position.0 - x).abs() <= tolerance
+                        && (entity.position.1 - y).abs() <= tolerance
+                })
+            })
+    }
+
+    // The same positions after a round trip through the quantized wire format
+    pub fn quantized(&self) -> Self {
         PositionData {
-            player1_position,
-            player2_position,
-            ball_position,
+            entities: self
+                .entities
+                .iter()
+                .map(|entity| Entity::new(entity.id, quantize_position(entity.position)))
+                .collect(),
         }
     }
 }
 
+// Panics if the entity isn't on the field, use get for a fallible lookup
 impl Index<EntityId> for PositionData {
     type Output = Position;
 
     fn index(&self, entity_id: EntityId) -> &Self::Output {
-        match entity_id {
-            EntityId::Player(PlayerId::Player1) => &self.player1_position,
-            EntityId::Player(PlayerId::Player2) => &self.player2_position,
-            EntityId::Ball => &self.ball_position,
-        }
+        self.entities
+            .iter()
+            .find(|entity| entity.id == entity_id)
+            .map(|entity| &entity.position)
+            .unwrap_or_else(|| panic!("No position for {:?}", entity_id))
     }
 }
 
 impl IndexMut<EntityId> for PositionData {
     fn index_mut(&mut self, entity_id: EntityId) -> &mut Self::Output {
-        match entity_id {
-            EntityId::Player(PlayerId::Player1) => &mut self.player1_position,
-            EntityId::Player(PlayerId::Player2) => &mut self.player2_position,
-            EntityId::Ball => &mut self.ball_position,
-        }
+        self.entities
+            .iter_mut()
+            .find(|entity| entity.id == entity_id)
+            .map(|entity| &mut entity.position)
+            .unwrap_or_else(|| panic!("No position for {:?}", entity_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_by_entity() {
+        let mut positions = PositionData::new((0.5, 0.1), (0.5, 0.9), (0.3, 0.7));
+        assert_eq!(positions[EntityId::Player(PlayerId::PLAYER_2)], (0.5, 0.9));
+
+        positions[EntityId::Ball] = (0.4, 0.6);
+        assert_eq!(
+            positions.get_classic(),
+            Some(((0.5, 0.1), (0.5, 0.9), (0.4, 0.6)))
+        );
+    }
+
+    #[test]
+    fn test_more_than_two_players() {
+        let mut positions = PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5));
+        let player3 = EntityId::Player(PlayerId::new(2));
+        assert_eq!(positions.get(player3), None);
+
+        positions.set(player3, (0.1, 0.5));
+        assert_eq!(positions[player3], (0.1, 0.5));
+        assert_eq!(positions.get_entities().len(), 4);
+    }
+
+    #[test]
+    fn test_classic_view_needs_both_paddles() {
+        let positions = PositionData::from_entities(vec![Entity::new(EntityId::Ball, (0.5, 0.5))]);
+        assert_eq!(positions.get_classic(), None);
+    }
+
+    #[test]
+    fn test_within_tolerance() {
+        let positions = PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5));
+        let nudged = PositionData::new((0.505, 0.1), (0.5, 0.9), (0.5, 0.495));
+
+        assert!(nudged.is_within(&positions, 0.01));
+        assert!(!nudged.is_within(&positions, 0.001));
+
+        let mut extra = positions.clone();
+        extra.set(EntityId::Player(PlayerId::new(2)), (0.1, 0.5));
+        assert!(!extra.is_within(&positions, 1.0));
     }
 }
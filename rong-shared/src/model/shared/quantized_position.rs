@@ -0,0 +1,86 @@
+use super::Position;
+
+/*
+    16-bit fixed point encoding for normalized (0..1) positions.
+
+    Each coordinate is stored as round(value * 65535), so the grid step is
+    1 / 65535 and any value in 0..1 decodes to within MAX_QUANTIZATION_ERROR
+    (half a step, ~7.6e-6) of the original. Values outside 0..1 are clamped.
+*/
+pub const QUANTIZATION_SCALE: f32 = u16::MAX as f32;
+pub const MAX_QUANTIZATION_ERROR: f32 = 0.5 / QUANTIZATION_SCALE;
+
+pub fn quantize(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * QUANTIZATION_SCALE).round() as u16
+}
+
+pub fn dequantize(value: u16) -> f32 {
+    value as f32 / QUANTIZATION_SCALE
+}
+
+// Snap a position onto the grid, exactly as it would come off the wire
+pub fn quantize_position(position: Position) -> Position {
+    (
+        dequantize(quantize(position.0)),
+        dequantize(quantize(position.1)),
+    )
+}
+
+// serde `with` module used by PositionData when quantized-positions is enabled
+#[cfg(feature = "quantized-positions")]
+pub(super) mod wire {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(position: &Position, serializer: S) -> Result<S::Ok, S::Error> {
+        (quantize(position.0), quantize(position.1)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Position, D::Error> {
+        let (x, y) = <(u16, u16)>::deserialize(deserializer)?;
+        Ok((dequantize(x), dequantize(y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_bound() {
+        for i in 0..=10_000 {
+            let value = i as f32 / 10_000.0;
+            let error = (dequantize(quantize(value)) - value).abs();
+            assert!(
+                error <= MAX_QUANTIZATION_ERROR,
+                "{} decoded with error {}",
+                value,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantized_position_is_stable() {
+        let snapped = quantize_position((0.123456, 0.654321));
+        assert_eq!(quantize_position(snapped), snapped);
+    }
+
+    #[cfg(feature = "quantized-positions")]
+    #[test]
+    fn test_position_data_wire_size() {
+        let positions = super::super::PositionData::new((0.5, 0.1), (0.5, 0.9), (0.3, 0.7));
+        let bytes = bincode::serialize(&positions).unwrap();
+        // Length prefix, then per entity a tag, a seat for paddles and two u16s
+        assert_eq!(bytes.len(), 8 + 2 * (4 + 1 + 4) + (4 + 4));
+
+        let decoded: super::super::PositionData = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, positions.quantized());
+    }
+
+    #[test]
+    fn test_out_of_range_is_clamped() {
+        assert_eq!(quantize(-0.5), 0);
+        assert_eq!(quantize(1.5), u16::MAX);
+    }
+}
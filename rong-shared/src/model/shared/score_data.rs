@@ -3,18 +3,34 @@ use super::{PlayerId, Score};
 use serde::{Deserialize, Serialize};
 use std::ops::{Index, IndexMut};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Seats that haven't scored yet don't need an entry
+const NO_SCORE: Score = Score(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ScoreData {
-    player1_score: Score,
-    player2_score: Score,
+    scores: Vec<(PlayerId, Score)>,
 }
 
 impl ScoreData {
+    // The classic layout, two players
     pub fn new(player1_score: Score, player2_score: Score) -> Self {
-        Self {
-            player1_score,
-            player2_score,
-        }
+        ScoreData::from_scores(vec![
+            (PlayerId::PLAYER_1, player1_score),
+            (PlayerId::PLAYER_2, player2_score),
+        ])
+    }
+
+    pub fn from_scores(scores: Vec<(PlayerId, Score)>) -> Self {
+        Self { scores }
+    }
+
+    pub fn get_scores(&self) -> &[(PlayerId, Score)] {
+        &self.scores
+    }
+
+    // Player 1 and player 2, for code written against the classic layout
+    pub fn get_classic(&self) -> (Score, Score) {
+        (self[PlayerId::PLAYER_1], self[PlayerId::PLAYER_2])
     }
 }
 
@@ -22,18 +38,41 @@ impl Index<PlayerId> for ScoreData {
     type Output = Score;
 
     fn index(&self, player_id: PlayerId) -> &Self::Output {
-        match player_id {
-            PlayerId::Player1 => &self.player1_score,
-            PlayerId::Player2 => &self.player2_score,
-        }
+        self.scores
+            .iter()
+            .find(|(id, _)| *id == player_id)
+            .map(|(_, score)| score)
+            .unwrap_or(&NO_SCORE)
     }
 }
 
+// Adds the player with no points if they aren't listed yet
 impl IndexMut<PlayerId> for ScoreData {
-    fn index_mut(&mut self, index: PlayerId) -> &mut Self::Output {
-        match index {
-            PlayerId::Player1 => &mut self.player1_score,
-            PlayerId::Player2 => &mut self.player2_score,
-        }
+    fn index_mut(&mut self, player_id: PlayerId) -> &mut Self::Output {
+        let index = match self.scores.iter().position(|(id, _)| *id == player_id) {
+            Some(index) => index,
+            None => {
+                self.scores.push((player_id, NO_SCORE));
+                self.scores.len() - 1
+            }
+        };
+        &mut self.scores[index].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_by_player() {
+        let mut scores = ScoreData::new(Score::new(3), Score::default());
+        let player3 = PlayerId::new(2);
+        assert_eq!(scores[player3], Score::default());
+
+        scores[player3] = Score::new(1);
+        scores[PlayerId::PLAYER_2] = Score::new(5);
+        assert_eq!(scores.get_classic(), (Score::new(3), Score::new(5)));
+        assert_eq!(scores.get_scores().len(), 3);
     }
 }
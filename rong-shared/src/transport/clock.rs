@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Offsets are taken from the fastest of the last few round trips, since
+// those spent the least time sitting in queues
+const OFFSET_SAMPLES: usize = 16;
+
+// Milliseconds since the Unix epoch, what NetworkPacket timestamps carry
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    rtt: u64,
+    offset: i64,
+}
+
+/*
+    Round trip time and remote clock offset, fed by ping/pong exchanges.
+
+    A Ping goes out stamped with our clock, the Pong echoes that stamp and
+    carries the peer's clock in its own packet timestamp. Assuming the two
+    legs take equally long, the peer read its clock halfway through.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    smoothed_rtt: Option<Duration>,
+    latest_rtt: Option<Duration>,
+    samples: VecDeque<Sample>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ping_time and now are our clock, pong_time is the peer's
+    pub fn record(&mut self, ping_time: u64, pong_time: u64, now: u64) {
+        let rtt = now.saturating_sub(ping_time);
+        let offset = pong_time as i64 - (ping_time + rtt / 2) as i64;
+
+        let rtt_duration = Duration::from_millis(rtt);
+        self.latest_rtt = Some(rtt_duration);
+        // Same smoothing factor as TCP's SRTT
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => (smoothed * 7 + rtt_duration) / 8,
+            None => rtt_duration,
+        });
+
+        if self.samples.len() == OFFSET_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { rtt, offset });
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    pub fn latest_rtt(&self) -> Option<Duration> {
+        self.latest_rtt
+    }
+
+    // Peer clock minus ours, in milliseconds
+    pub fn offset_millis(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.rtt)
+            .map(|sample| sample.offset)
+    }
+
+    // One of our timestamps on the peer's clock
+    pub fn to_remote_time(&self, local_time: u64) -> Option<u64> {
+        self.offset_millis()
+            .map(|offset| local_time.saturating_add_signed(offset))
+    }
+
+    // One of the peer's timestamps on our clock
+    pub fn to_local_time(&self, remote_time: u64) -> Option<u64> {
+        self.offset_millis()
+            .map(|offset| remote_time.saturating_add_signed(-offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symmetric_round_trip() {
+        let mut clock = ClockSync::new();
+        // Peer is 5 seconds ahead, each leg takes 20ms
+        clock.record(1_000, 6_020, 1_040);
+
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(clock.offset_millis(), Some(5_000));
+        assert_eq!(clock.to_remote_time(2_000), Some(7_000));
+        assert_eq!(clock.to_local_time(7_000), Some(2_000));
+    }
+
+    #[test]
+    fn test_offset_prefers_fastest_round_trip() {
+        let mut clock = ClockSync::new();
+        clock.record(1_000, 1_030, 1_040);
+        // Stuck in a queue on the way back, skews the naive estimate
+        clock.record(2_000, 2_020, 2_300);
+
+        assert_eq!(clock.offset_millis(), Some(10));
+        assert_eq!(clock.latest_rtt(), Some(Duration::from_millis(300)));
+        assert!(clock.rtt().unwrap() > Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_no_samples_yet() {
+        let clock = ClockSync::new();
+        assert_eq!(clock.rtt(), None);
+        assert_eq!(clock.to_remote_time(1_000), None);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::{CodecError, DecodeError};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+/*
+    Turns packets into bytes and back.
+
+    decode must never read or allocate more than bytes.len(), and must fail
+    rather than panic on arbitrary input.
+
+    Every packet goes through the one codec, Hello included. A peer on another
+    codec has its Hello fail to decode and dropped like any other garbage, so
+    it never gets a VersionMismatch back, it just never hears a Welcome.
+*/
+pub trait WireCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError>;
+}
+
+pub struct BincodeCodec;
+
+impl BincodeCodec {
+    // Same layout as bincode::serialize, but a length prefix can't claim more
+    // bytes than the packet actually has
+    fn options(limit: usize) -> impl Options {
+        bincode::options()
+            .with_fixint_encoding()
+            .with_limit(limit as u64)
+    }
+}
+
+impl WireCodec for BincodeCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+        Self::options(bytes.len())
+            .deserialize(bytes)
+            .map_err(|e| match *e {
+                bincode::ErrorKind::SizeLimit => DecodeError::Truncated,
+                bincode::ErrorKind::Io(ref io)
+                    if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    DecodeError::Truncated
+                }
+                _ => DecodeError::Malformed(e.to_string()),
+            })
+    }
+}
+
+// Compact varint encoding for production traffic
+#[cfg(feature = "postcard")]
+pub struct PostcardCodec;
+
+#[cfg(feature = "postcard")]
+impl WireCodec for PostcardCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        postcard::to_stdvec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+        postcard::from_bytes(bytes).map_err(|e| match e {
+            postcard::Error::DeserializeUnexpectedEnd => DecodeError::Truncated,
+            _ => DecodeError::Malformed(e.to_string()),
+        })
+    }
+}
+
+// Human readable, for inspecting traffic while debugging
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl WireCodec for JsonCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+        serde_json::from_slice(bytes).map_err(|e| match e.classify() {
+            serde_json::error::Category::Eof => DecodeError::Truncated,
+            _ => DecodeError::Malformed(e.to_string()),
+        })
+    }
+}
+
+// Both ends must be built with the same codec features to understand each other
+#[cfg(feature = "json")]
+pub type DefaultCodec = JsonCodec;
+#[cfg(all(feature = "postcard", not(feature = "json")))]
+pub type DefaultCodec = PostcardCodec;
+#[cfg(not(any(feature = "postcard", feature = "json")))]
+pub type DefaultCodec = BincodeCodec;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ClientMessage, NetworkPacket, PROTOCOL_VERSION};
+
+    fn round_trip<C: WireCodec>() {
+        let packet = NetworkPacket::new(
+            7,
+            42,
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_build: "test".to_string(),
+            },
+        );
+
+        let bytes = C::encode(&packet).unwrap();
+        let decoded: NetworkPacket<ClientMessage> = C::decode(&bytes).unwrap();
+
+        assert_eq!(decoded.get_sequence(), 7);
+        assert_eq!(decoded.get_timestamp(), 42);
+        assert!(matches!(
+            decoded.get_payload(),
+            ClientMessage::Hello { protocol_version, .. } if *protocol_version == PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn test_bincode_round_trip() {
+        round_trip::<BincodeCodec>();
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard_round_trip() {
+        round_trip::<PostcardCodec>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_round_trip() {
+        round_trip::<JsonCodec>();
+    }
+
+    #[test]
+    fn test_garbage_fails_to_decode() {
+        let result = DefaultCodec::decode::<NetworkPacket<ClientMessage>>(&[0xff; 3]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_huge_length_prefix_is_rejected() {
+        // A Hello whose client_build claims to be u64::MAX bytes long
+        let mut bytes = BincodeCodec::encode(&NetworkPacket::new(
+            1,
+            0,
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_build: String::new(),
+            },
+        ))
+        .unwrap();
+        let prefix_start = bytes.len() - 8;
+        bytes[prefix_start..].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let result = BincodeCodec::decode::<NetworkPacket<ClientMessage>>(&bytes);
+        assert!(matches!(result, Err(DecodeError::Truncated)));
+    }
+}
@@ -0,0 +1,305 @@
+use crate::error::ConditionsError;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Held back datagrams wait this much longer than any normal one could
+const REORDER_MARGIN: Duration = Duration::from_millis(10);
+
+/*
+    A bad network to play over on one machine, set from the command line:
+
+        --latency <ms>      added to every datagram
+        --jitter <ms>       latency varies by up to this much either way
+        --loss <percent>    datagrams dropped
+        --duplicate <percent>
+        --reorder <percent> datagrams held back until later ones overtake them
+
+    Conditions apply to what a side sends, give both sides flags to degrade
+    both directions.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    // Probabilities from 0 to 1
+    pub loss: f32,
+    pub duplicate: f32,
+    pub reorder: f32,
+}
+
+impl NetworkConditions {
+    // Picks the flags above out of args, leaving the rest for the caller
+    pub fn from_args<I>(args: I) -> Result<Self, ConditionsError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let mut conditions = NetworkConditions::default();
+        let mut args = args.into_iter().map(Into::into);
+
+        while let Some(flag) = args.next() {
+            if !matches!(
+                flag.as_str(),
+                "--latency" | "--jitter" | "--loss" | "--duplicate" | "--reorder"
+            ) {
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| ConditionsError::MissingValue(flag.clone()))?;
+            let invalid = || ConditionsError::InvalidValue {
+                flag: flag.clone(),
+                value: value.clone(),
+            };
+
+            match flag.as_str() {
+                "--latency" | "--jitter" => {
+                    let millis: u64 = value.parse().map_err(|_| invalid())?;
+                    let millis = Duration::from_millis(millis);
+                    if flag == "--latency" {
+                        conditions.latency = millis;
+                    } else {
+                        conditions.jitter = millis;
+                    }
+                }
+                _ => {
+                    let percent: f32 = value.parse().map_err(|_| invalid())?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(invalid());
+                    }
+                    let probability = percent / 100.0;
+                    match flag.as_str() {
+                        "--loss" => conditions.loss = probability,
+                        "--duplicate" => conditions.duplicate = probability,
+                        _ => conditions.reorder = probability,
+                    }
+                }
+            }
+        }
+        Ok(conditions)
+    }
+
+    // Nothing to simulate, callers can skip the simulator entirely
+    pub fn is_perfect(&self) -> bool {
+        *self == NetworkConditions::default()
+    }
+}
+
+// Due time, then send order so ties leave in the order they came
+type Queued = Reverse<(Instant, u64, Vec<u8>, SocketAddr)>;
+
+/*
+    Decides what the network does to each datagram, and for callers without
+    a runtime to sleep on, holds them until they're due.
+*/
+#[derive(Debug)]
+pub struct LinkSimulator {
+    conditions: NetworkConditions,
+    rng: StdRng,
+    queue: BinaryHeap<Queued>,
+    pushed: u64,
+}
+
+impl LinkSimulator {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        LinkSimulator::with_rng(conditions, StdRng::from_entropy())
+    }
+
+    // Same seed, same fate for every datagram
+    pub fn with_seed(conditions: NetworkConditions, seed: u64) -> Self {
+        LinkSimulator::with_rng(conditions, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(conditions: NetworkConditions, rng: StdRng) -> Self {
+        LinkSimulator {
+            conditions,
+            rng,
+            queue: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    pub fn get_conditions(&self) -> NetworkConditions {
+        self.conditions
+    }
+
+    // How long until each copy of the next datagram arrives. Empty if it's
+    // lost, two entries if it's duplicated
+    pub fn delays(&mut self) -> Vec<Duration> {
+        let conditions = self.conditions;
+        if self.rng.gen::<f32>() < conditions.loss {
+            return Vec::new();
+        }
+        let copies = if self.rng.gen::<f32>() < conditions.duplicate {
+            2
+        } else {
+            1
+        };
+
+        (0..copies)
+            .map(|_| {
+                let offset = self.rng.gen_range(-1.0..=1.0f32);
+                let spread = conditions.jitter.mul_f32(offset.abs());
+                let mut delay = if offset >= 0.0 {
+                    conditions.latency + spread
+                } else {
+                    conditions.latency.saturating_sub(spread)
+                };
+                if self.rng.gen::<f32>() < conditions.reorder {
+                    delay += conditions.latency + conditions.jitter + REORDER_MARGIN;
+                }
+                delay
+            })
+            .collect()
+    }
+
+    pub fn push(&mut self, datagram: &[u8], addr: SocketAddr, now: Instant) {
+        for delay in self.delays() {
+            self.pushed += 1;
+            self.queue
+                .push(Reverse((now + delay, self.pushed, datagram.to_vec(), addr)));
+        }
+    }
+
+    // Datagrams whose time has come, in the order they arrive
+    pub fn pop_due(&mut self, now: Instant) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut due = Vec::new();
+        while self
+            .queue
+            .peek()
+            .is_some_and(|Reverse((at, ..))| *at <= now)
+        {
+            let Reverse((_, _, datagram, addr)) = self.queue.pop().expect("peeked");
+            due.push((datagram, addr));
+        }
+        due
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:2906".parse().unwrap()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let args = [
+            "rong",
+            "--latency",
+            "80",
+            "--name",
+            "x",
+            "--loss",
+            "5",
+            "--reorder",
+            "2.5",
+        ];
+        let conditions = NetworkConditions::from_args(args).unwrap();
+
+        assert_eq!(conditions.latency, Duration::from_millis(80));
+        assert_eq!(conditions.jitter, Duration::ZERO);
+        assert_eq!(conditions.loss, 0.05);
+        assert_eq!(conditions.reorder, 0.025);
+        assert!(NetworkConditions::from_args(["rong"]).unwrap().is_perfect());
+    }
+
+    #[test]
+    fn test_bad_args() {
+        assert_eq!(
+            NetworkConditions::from_args(["--loss", "150"]),
+            Err(ConditionsError::InvalidValue {
+                flag: "--loss".to_string(),
+                value: "150".to_string(),
+            })
+        );
+        assert_eq!(
+            NetworkConditions::from_args(["--jitter"]),
+            Err(ConditionsError::MissingValue("--jitter".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_perfect_link_passes_straight_through() {
+        let mut link = LinkSimulator::with_seed(NetworkConditions::default(), 1);
+        let now = Instant::now();
+        link.push(b"a", addr(), now);
+        link.push(b"b", addr(), now);
+
+        let due: Vec<Vec<u8>> = link.pop_due(now).into_iter().map(|(d, _)| d).collect();
+        assert_eq!(due, vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn test_latency_loss_and_duplication() {
+        let now = Instant::now();
+        let latency = Duration::from_millis(50);
+
+        let mut slow = LinkSimulator::with_seed(
+            NetworkConditions {
+                latency,
+                ..Default::default()
+            },
+            1,
+        );
+        slow.push(b"a", addr(), now);
+        assert!(slow.pop_due(now + latency / 2).is_empty());
+        assert_eq!(slow.pop_due(now + latency).len(), 1);
+
+        let mut lossy = LinkSimulator::with_seed(
+            NetworkConditions {
+                loss: 1.0,
+                ..Default::default()
+            },
+            1,
+        );
+        lossy.push(b"a", addr(), now);
+        assert_eq!(lossy.pending_count(), 0);
+
+        let mut doubled = LinkSimulator::with_seed(
+            NetworkConditions {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+            1,
+        );
+        doubled.push(b"a", addr(), now);
+        assert_eq!(doubled.pop_due(now).len(), 2);
+    }
+
+    #[test]
+    fn test_reordering() {
+        let mut link = LinkSimulator::with_seed(
+            NetworkConditions {
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(5),
+                reorder: 0.3,
+                ..Default::default()
+            },
+            7,
+        );
+        let now = Instant::now();
+        for i in 0..50u8 {
+            link.push(&[i], addr(), now);
+        }
+
+        let arrived: Vec<u8> = link
+            .pop_due(now + Duration::from_secs(1))
+            .into_iter()
+            .map(|(datagram, _)| datagram[0])
+            .collect();
+        assert_eq!(arrived.len(), 50);
+        assert!(arrived.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+}
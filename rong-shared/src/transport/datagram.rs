@@ -0,0 +1,129 @@
+use super::{read_header, write_header, FragmentHeader, WireCodec, HEADER_SIZE};
+use crate::error::{CodecError, DecodeError};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+// Largest datagram we send or accept, header included. Kept under the
+// common 1280 byte IPv6 minimum MTU so packets are never IP-fragmented
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+// One spare byte so an oversized datagram shows up as too large instead of
+// being silently truncated by recv_from
+pub const RECEIVE_BUFFER_SIZE: usize = MAX_DATAGRAM_SIZE + 1;
+
+// The first body byte says whether a whole packet or a fragment follows
+const FRAME_WHOLE: u8 = 0;
+const FRAME_FRAGMENT: u8 = 1;
+pub const FRAME_OVERHEAD: usize = HEADER_SIZE + 1;
+
+#[derive(Debug)]
+pub enum Frame<'a> {
+    Whole(&'a [u8]),
+    Fragment(FragmentHeader, &'a [u8]),
+}
+
+// Size check, then header check, then frame kind, all before any decoding
+pub fn open_datagram(datagram: &[u8]) -> Result<Frame<'_>, DecodeError> {
+    if datagram.len() > MAX_DATAGRAM_SIZE {
+        return Err(DecodeError::TooLarge {
+            size: datagram.len(),
+            limit: MAX_DATAGRAM_SIZE,
+        });
+    }
+
+    match read_header(datagram)?.split_first() {
+        Some((&FRAME_WHOLE, body)) => Ok(Frame::Whole(body)),
+        Some((&FRAME_FRAGMENT, rest)) => {
+            let (header, chunk) = FragmentHeader::read(rest)?;
+            Ok(Frame::Fragment(header, chunk))
+        }
+        Some((kind, _)) => Err(DecodeError::Malformed(format!(
+            "Unknown frame kind {}",
+            kind
+        ))),
+        None => Err(DecodeError::Truncated),
+    }
+}
+
+pub fn encode_datagram<C: WireCodec, T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    whole_datagram(&C::encode(value)?)
+}
+
+// Whole packets only, peers that may fragment need a Reassembler
+pub fn decode_datagram<C: WireCodec, T: DeserializeOwned>(
+    datagram: &[u8],
+) -> Result<T, DecodeError> {
+    match open_datagram(datagram)? {
+        Frame::Whole(body) => C::decode(body),
+        Frame::Fragment(..) => Err(DecodeError::Malformed("Unexpected fragment".to_string())),
+    }
+}
+
+pub(super) fn whole_datagram(body: &[u8]) -> Result<Vec<u8>, CodecError> {
+    if FRAME_OVERHEAD + body.len() > MAX_DATAGRAM_SIZE {
+        return Err(CodecError::Encode(format!(
+            "{} byte packet exceeds the {} byte datagram limit",
+            FRAME_OVERHEAD + body.len(),
+            MAX_DATAGRAM_SIZE
+        )));
+    }
+
+    let mut framed = Vec::with_capacity(1 + body.len());
+    framed.push(FRAME_WHOLE);
+    framed.extend_from_slice(body);
+    Ok(write_header(&framed))
+}
+
+pub(super) fn fragment_datagram(header: FragmentHeader, chunk: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(1 + FragmentHeader::SIZE + chunk.len());
+    framed.push(FRAME_FRAGMENT);
+    header.write(&mut framed);
+    framed.extend_from_slice(chunk);
+    write_header(&framed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ClientMessage, NetworkPacket, ServerMessage};
+    use crate::transport::DefaultCodec;
+
+    #[test]
+    fn test_oversized_datagram_is_rejected() {
+        let datagram = vec![0; RECEIVE_BUFFER_SIZE];
+        let result = decode_datagram::<DefaultCodec, NetworkPacket<ClientMessage>>(&datagram);
+        assert!(matches!(result, Err(DecodeError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn test_oversized_packet_is_not_sent() {
+        let packet = NetworkPacket::new(
+            1,
+            0,
+            ClientMessage::Hello {
+                protocol_version: 0,
+                client_build: "x".repeat(MAX_DATAGRAM_SIZE),
+            },
+        );
+        assert!(encode_datagram::<DefaultCodec, _>(&packet).is_err());
+    }
+
+    // Cheap stand-in for the fuzz targets so regressions show up in cargo test
+    #[test]
+    fn test_arbitrary_bytes_never_panic() {
+        let mut state: u32 = 0x2906;
+        let mut next_byte = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        };
+
+        for len in 0..512 {
+            let body: Vec<u8> = (0..len).map(|_| next_byte()).collect();
+            let datagram = write_header(&body);
+            let _ = decode_datagram::<DefaultCodec, NetworkPacket<ClientMessage>>(&datagram);
+            let _ = decode_datagram::<DefaultCodec, NetworkPacket<ServerMessage>>(&datagram);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use super::datagram::{fragment_datagram, open_datagram, whole_datagram, Frame, FRAME_OVERHEAD};
+use super::{WireCodec, MAX_DATAGRAM_SIZE};
+use crate::error::{CodecError, DecodeError};
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Largest message we will split up or put back together
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const MAX_FRAGMENT_PAYLOAD: usize = MAX_DATAGRAM_SIZE - FRAME_OVERHEAD - FragmentHeader::SIZE;
+const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE.div_ceil(MAX_FRAGMENT_PAYLOAD);
+
+// Partial messages still missing fragments after this are thrown away
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+/*  | message id (2, LE) | fragment index (1) | fragment count (1) | */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    message_id: u16,
+    index: u8,
+    count: u8,
+}
+
+impl FragmentHeader {
+    pub const SIZE: usize = 4;
+
+    pub(super) fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.message_id.to_le_bytes());
+        out.push(self.index);
+        out.push(self.count);
+    }
+
+    pub(super) fn read(bytes: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if bytes.len() < Self::SIZE {
+            return Err(DecodeError::Truncated);
+        }
+        let (header, chunk) = bytes.split_at(Self::SIZE);
+        let header = FragmentHeader {
+            message_id: u16::from_le_bytes([header[0], header[1]]),
+            index: header[2],
+            count: header[3],
+        };
+
+        if header.count == 0 || header.count as usize > MAX_FRAGMENTS {
+            return Err(DecodeError::Malformed(format!(
+                "Fragment count {} outside 1..={}",
+                header.count, MAX_FRAGMENTS
+            )));
+        }
+        if header.index >= header.count {
+            return Err(DecodeError::Malformed(format!(
+                "Fragment index {} of {}",
+                header.index, header.count
+            )));
+        }
+        Ok((header, chunk))
+    }
+}
+
+/*  Sending side: packets that don't fit in one datagram are split up */
+#[derive(Debug, Clone, Default)]
+pub struct Fragmenter {
+    next_message_id: u16,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode<C: WireCodec, T: Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        self.encode_body(&C::encode(value)?)
+    }
+
+    // For bodies that were already encoded, e.g. sealed by a SecureChannel
+    pub fn encode_body(&mut self, body: &[u8]) -> Result<Vec<Vec<u8>>, CodecError> {
+        if FRAME_OVERHEAD + body.len() <= MAX_DATAGRAM_SIZE {
+            return Ok(vec![whole_datagram(body)?]);
+        }
+
+        if body.len() > MAX_MESSAGE_SIZE {
+            return Err(CodecError::Encode(format!(
+                "{} byte message exceeds the {} byte limit",
+                body.len(),
+                MAX_MESSAGE_SIZE
+            )));
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        let chunks = body.chunks(MAX_FRAGMENT_PAYLOAD);
+        let count = chunks.len() as u8;
+        Ok(chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let header = FragmentHeader {
+                    message_id,
+                    index: index as u8,
+                    count,
+                };
+                fragment_datagram(header, chunk)
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    first_seen: Instant,
+}
+
+/*  Receiving side, one per peer: buffers fragments until a message is complete */
+#[derive(Debug, Clone, Default)]
+pub struct Reassembler {
+    partial: HashMap<u16, PartialMessage>,
+    expired: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the message body once its last fragment arrives
+    pub fn accept(
+        &mut self,
+        header: FragmentHeader,
+        chunk: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, DecodeError> {
+        self.expire(now);
+
+        if !self.partial.contains_key(&header.message_id)
+            && self.partial.len() >= MAX_PARTIAL_MESSAGES
+        {
+            self.evict_oldest();
+        }
+
+        let partial = self
+            .partial
+            .entry(header.message_id)
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; header.count as usize],
+                received: 0,
+                size: 0,
+                first_seen: now,
+            });
+
+        if partial.fragments.len() != header.count as usize {
+            self.partial.remove(&header.message_id);
+            return Err(DecodeError::Malformed(
+                "Fragment count changed mid-message".to_string(),
+            ));
+        }
+
+        let slot = &mut partial.fragments[header.index as usize];
+        if slot.is_some() {
+            // Duplicate fragment
+            return Ok(None);
+        }
+
+        if partial.size + chunk.len() > MAX_MESSAGE_SIZE {
+            let size = partial.size + chunk.len();
+            self.partial.remove(&header.message_id);
+            return Err(DecodeError::TooLarge {
+                size,
+                limit: MAX_MESSAGE_SIZE,
+            });
+        }
+
+        *slot = Some(chunk.to_vec());
+        partial.received += 1;
+        partial.size += chunk.len();
+
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+
+        let partial = self
+            .partial
+            .remove(&header.message_id)
+            .expect("partial message exists");
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    // Decode a datagram from this peer, None while a fragmented message is incomplete
+    pub fn decode<C: WireCodec, T: DeserializeOwned>(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<T>, DecodeError> {
+        match open_datagram(datagram)? {
+            Frame::Whole(body) => C::decode(body).map(Some),
+            Frame::Fragment(header, chunk) => match self.accept(header, chunk, now)? {
+                Some(body) => C::decode(&body).map(Some),
+                None => Ok(None),
+            },
+        }
+    }
+
+    // Same as decode, but hands back the raw body for callers that unseal it first
+    pub fn receive(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, DecodeError> {
+        match open_datagram(datagram)? {
+            Frame::Whole(body) => Ok(Some(body.to_vec())),
+            Frame::Fragment(header, chunk) => self.accept(header, chunk, now),
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let before = self.partial.len();
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.first_seen) < FRAGMENT_TIMEOUT);
+        self.expired += (before - self.partial.len()) as u64;
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.partial.len()
+    }
+
+    pub fn expired_count(&self) -> u64 {
+        self.expired
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(oldest) = self
+            .partial
+            .iter()
+            .min_by_key(|(_, partial)| partial.first_seen)
+            .map(|(id, _)| *id)
+        {
+            self.partial.remove(&oldest);
+            self.expired += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ClientMessage, NetworkPacket};
+    use crate::transport::DefaultCodec;
+
+    fn large_packet(size: usize) -> NetworkPacket<ClientMessage> {
+        NetworkPacket::new(
+            1,
+            0,
+            ClientMessage::Hello {
+                protocol_version: 0,
+                client_build: "x".repeat(size),
+            },
+        )
+    }
+
+    fn client_build(packet: &NetworkPacket<ClientMessage>) -> &str {
+        match packet.get_payload() {
+            ClientMessage::Hello { client_build, .. } => client_build,
+            _ => panic!("Expected Hello"),
+        }
+    }
+
+    #[test]
+    fn test_small_packet_is_not_fragmented() {
+        let datagrams = Fragmenter::new()
+            .encode::<DefaultCodec, _>(&large_packet(10))
+            .unwrap();
+        assert_eq!(datagrams.len(), 1);
+    }
+
+    #[test]
+    fn test_out_of_order_reassembly() {
+        let datagrams = Fragmenter::new()
+            .encode::<DefaultCodec, _>(&large_packet(5000))
+            .unwrap();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_SIZE));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        let mut decoded = None;
+        for datagram in datagrams.iter().rev() {
+            assert!(decoded.is_none(), "Message completed early");
+            decoded = reassembler
+                .decode::<DefaultCodec, NetworkPacket<ClientMessage>>(datagram, now)
+                .unwrap();
+        }
+
+        assert_eq!(client_build(&decoded.unwrap()).len(), 5000);
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_message_times_out() {
+        let datagrams = Fragmenter::new()
+            .encode::<DefaultCodec, _>(&large_packet(5000))
+            .unwrap();
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        reassembler
+            .decode::<DefaultCodec, NetworkPacket<ClientMessage>>(&datagrams[0], now)
+            .unwrap();
+        assert_eq!(reassembler.pending_count(), 1);
+
+        reassembler.expire(now + FRAGMENT_TIMEOUT);
+        assert_eq!(reassembler.pending_count(), 0);
+        assert_eq!(reassembler.expired_count(), 1);
+    }
+
+    #[test]
+    fn test_oversized_message_is_refused() {
+        let result = Fragmenter::new().encode::<DefaultCodec, _>(&large_packet(MAX_MESSAGE_SIZE));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_bogus_fragment_header_is_rejected() {
+        let header = FragmentHeader {
+            message_id: 0,
+            index: 3,
+            count: 2,
+        };
+        let datagram = fragment_datagram(header, b"chunk");
+        assert!(matches!(
+            open_datagram(&datagram),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::HeaderError;
+
+/*
+    Every datagram starts with a fixed header so stray traffic can be
+    rejected before it ever reaches the codec:
+
+    | magic (2) | protocol id (2, LE) | crc32 of body (4, LE) | body ... |
+*/
+pub const PACKET_MAGIC: [u8; 2] = *b"RG";
+// Identifies the datagram framing itself. Message layout changes bump
+// PROTOCOL_VERSION instead, so mismatched clients still reach the handshake
+pub const PROTOCOL_ID: u16 = 2;
+pub const HEADER_SIZE: usize = 8;
+
+pub fn write_header(body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_SIZE + body.len());
+    datagram.extend_from_slice(&PACKET_MAGIC);
+    datagram.extend_from_slice(&PROTOCOL_ID.to_le_bytes());
+    datagram.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+// Returns the body if the header checks out
+pub fn read_header(datagram: &[u8]) -> Result<&[u8], HeaderError> {
+    if datagram.len() < HEADER_SIZE {
+        return Err(HeaderError::Truncated(datagram.len()));
+    }
+
+    let (header, body) = datagram.split_at(HEADER_SIZE);
+    if header[0..2] != PACKET_MAGIC {
+        return Err(HeaderError::BadMagic);
+    }
+
+    let protocol_id = u16::from_le_bytes([header[2], header[3]]);
+    if protocol_id != PROTOCOL_ID {
+        return Err(HeaderError::UnknownProtocol(protocol_id));
+    }
+
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if checksum != crc32fast::hash(body) {
+        return Err(HeaderError::BadChecksum);
+    }
+
+    Ok(body)
+}
+
+/*  Tally of datagrams dropped by read_header, by reason */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DroppedPackets {
+    pub truncated: u64,
+    pub bad_magic: u64,
+    pub unknown_protocol: u64,
+    pub bad_checksum: u64,
+}
+
+impl DroppedPackets {
+    pub fn record(&mut self, error: &HeaderError) {
+        match error {
+            HeaderError::Truncated(_) => self.truncated += 1,
+            HeaderError::BadMagic => self.bad_magic += 1,
+            HeaderError::UnknownProtocol(_) => self.unknown_protocol += 1,
+            HeaderError::BadChecksum => self.bad_checksum += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.truncated + self.bad_magic + self.unknown_protocol + self.bad_checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let datagram = write_header(b"payload");
+        assert_eq!(datagram.len(), HEADER_SIZE + 7);
+        assert_eq!(read_header(&datagram).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn test_rejects_stray_traffic() {
+        assert!(matches!(
+            read_header(b"GET"),
+            Err(HeaderError::Truncated(3))
+        ));
+        assert!(matches!(
+            read_header(b"GET / HTTP/1.1\r\n"),
+            Err(HeaderError::BadMagic)
+        ));
+
+        let mut datagram = write_header(b"payload");
+        datagram[2] = 0xff;
+        assert!(matches!(
+            read_header(&datagram),
+            Err(HeaderError::UnknownProtocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_corrupted_body() {
+        let mut datagram = write_header(b"payload");
+        *datagram.last_mut().unwrap() ^= 0x01;
+
+        let error = read_header(&datagram).unwrap_err();
+        assert!(matches!(error, HeaderError::BadChecksum));
+
+        let mut dropped = DroppedPackets::default();
+        dropped.record(&error);
+        assert_eq!(dropped.bad_checksum, 1);
+        assert_eq!(dropped.total(), 1);
+    }
+}
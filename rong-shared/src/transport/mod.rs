@@ -0,0 +1,48 @@
+mod clock;
+mod codec;
+mod conditions;
+mod datagram;
+mod fragment;
+mod header;
+mod rate;
+mod reliability;
+#[cfg(feature = "secure")]
+mod secure;
+mod snapshot;
+#[cfg(feature = "tokio")]
+mod socket;
+
+pub use clock::{now_millis, ClockSync};
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+#[cfg(feature = "postcard")]
+pub use codec::PostcardCodec;
+pub use codec::{BincodeCodec, DefaultCodec, WireCodec};
+pub use conditions::{LinkSimulator, NetworkConditions};
+pub use datagram::{
+    decode_datagram, encode_datagram, open_datagram, Frame, FRAME_OVERHEAD, MAX_DATAGRAM_SIZE,
+    RECEIVE_BUFFER_SIZE,
+};
+pub use fragment::{
+    FragmentHeader, Fragmenter, Reassembler, FRAGMENT_TIMEOUT, MAX_FRAGMENT_PAYLOAD,
+    MAX_MESSAGE_SIZE,
+};
+pub use header::{
+    read_header, write_header, DroppedPackets, HEADER_SIZE, PACKET_MAGIC, PROTOCOL_ID,
+};
+pub use rate::{SendRate, SendRateConfig};
+pub use reliability::{
+    Deliverable, Delivery, ReliabilityConfig, ReliabilityStats, ReliableEndpoint,
+};
+#[cfg(feature = "secure")]
+pub use secure::{
+    open_plain, Handshake, KeyExchange, Opened, Role, SecureChannel, SEALED_OVERHEAD,
+};
+pub use snapshot::{SnapshotDecoder, SnapshotEncoder};
+#[cfg(feature = "tokio")]
+pub use socket::{MemoryNetwork, MemoryTransport, SimulatedTransport, Transport};
+
+// Sequence comparison that survives u32 wraparound
+pub fn sequence_greater_than(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
@@ -0,0 +1,231 @@
+use super::sequence_greater_than;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Snapshots the loss estimate looks back over
+const LOSS_WINDOW: usize = 32;
+// Snapshots sent between decisions, so each one sees the effect of the last
+const ADAPT_EVERY: u32 = 8;
+// How much faster a healthy client gets per decision
+const SPEED_UP_STEP: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendRateConfig {
+    // The rate every client starts at and healthy ones stay at
+    pub min_interval: Duration,
+    // The slowest a congested client is ever updated
+    pub max_interval: Duration,
+    // Loss or round trip above these count as congestion
+    pub max_loss: f32,
+    pub max_rtt: Duration,
+    // How far positions may drift before being resent, reached at max_interval
+    pub max_position_tolerance: f32,
+}
+
+impl Default for SendRateConfig {
+    fn default() -> Self {
+        SendRateConfig {
+            min_interval: Duration::from_millis(50),
+            max_interval: Duration::from_millis(250),
+            max_loss: 0.1,
+            max_rtt: Duration::from_millis(200),
+            max_position_tolerance: 0.01,
+        }
+    }
+}
+
+/*
+    How often one client gets a GameUpdate, and how detailed it is.
+
+    Loss is measured from the client's SnapshotAcks: a snapshot still
+    unacked once a later one has been acked didn't make it. Congestion
+    doubles the interval, a healthy link takes it back down a step at a
+    time, always within the configured bounds. The slower the rate, the
+    more positions may drift before a delta bothers resending them.
+*/
+#[derive(Debug, Clone)]
+pub struct SendRate {
+    config: SendRateConfig,
+    interval: Duration,
+    last_sent: Option<Instant>,
+    // Recent snapshot ids, oldest first, and whether each was acked
+    window: VecDeque<(u32, bool)>,
+    sent_since_change: u32,
+}
+
+impl Default for SendRate {
+    fn default() -> Self {
+        SendRate::new(SendRateConfig::default())
+    }
+}
+
+impl SendRate {
+    pub fn new(config: SendRateConfig) -> Self {
+        SendRate {
+            config,
+            interval: config.min_interval,
+            last_sent: None,
+            window: VecDeque::with_capacity(LOSS_WINDOW),
+            sent_since_change: 0,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.last_sent
+            .is_none_or(|sent| now.duration_since(sent) >= self.interval)
+    }
+
+    pub fn record_sent(&mut self, id: u32, now: Instant) {
+        self.last_sent = Some(now);
+        self.sent_since_change += 1;
+        if self.window.len() == LOSS_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back((id, false));
+    }
+
+    pub fn record_ack(&mut self, id: u32) {
+        if let Some(entry) = self.window.iter_mut().find(|(sent, _)| *sent == id) {
+            entry.1 = true;
+        }
+    }
+
+    // Share of snapshots lost, out of those old enough to tell. None until
+    // there's something to go on
+    pub fn loss(&self) -> Option<f32> {
+        let newest_acked = self
+            .window
+            .iter()
+            .rev()
+            .find(|(_, acked)| *acked)
+            .map(|(id, _)| *id);
+
+        let judged: Vec<bool> = match newest_acked {
+            Some(newest) => self
+                .window
+                .iter()
+                .filter(|(id, _)| !sequence_greater_than(*id, newest))
+                .map(|(_, acked)| *acked)
+                .collect(),
+            // A full window with no acks at all is as lossy as it gets
+            None if self.window.len() == LOSS_WINDOW => vec![false; LOSS_WINDOW],
+            None => return None,
+        };
+
+        let lost = judged.iter().filter(|acked| !**acked).count();
+        Some(lost as f32 / judged.len() as f32)
+    }
+
+    // Called after each send with the client's smoothed round trip, changes
+    // the rate at most every few snapshots
+    pub fn adapt(&mut self, rtt: Option<Duration>) {
+        if self.sent_since_change < ADAPT_EVERY {
+            return;
+        }
+        self.sent_since_change = 0;
+
+        let congested = self.loss().is_some_and(|loss| loss > self.config.max_loss)
+            || rtt.is_some_and(|rtt| rtt > self.config.max_rtt);
+
+        if congested {
+            self.interval = (self.interval * 2).min(self.config.max_interval);
+            // Losses from the old rate shouldn't count against the new one
+            self.window.clear();
+        } else {
+            self.interval = self
+                .interval
+                .saturating_sub(SPEED_UP_STEP)
+                .max(self.config.min_interval);
+        }
+    }
+
+    pub fn get_interval(&self) -> Duration {
+        self.interval
+    }
+
+    // Zero at the fastest rate, max_position_tolerance at the slowest
+    pub fn get_position_tolerance(&self) -> f32 {
+        let range = self
+            .config
+            .max_interval
+            .saturating_sub(self.config.min_interval);
+        if range.is_zero() {
+            return 0.0;
+        }
+        let slowdown = self.interval.saturating_sub(self.config.min_interval);
+        self.config.max_position_tolerance * slowdown.as_secs_f32() / range.as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends a round of snapshots, acking the ones keep picks
+    fn send_round(
+        rate: &mut SendRate,
+        first_id: u32,
+        rtt: Option<Duration>,
+        keep: fn(u32) -> bool,
+    ) {
+        let now = Instant::now();
+        for id in first_id..first_id + ADAPT_EVERY {
+            rate.record_sent(id, now);
+            if keep(id) {
+                rate.record_ack(id);
+            }
+            rate.adapt(rtt);
+        }
+    }
+
+    #[test]
+    fn test_healthy_client_stays_at_full_rate() {
+        let mut rate = SendRate::default();
+        send_round(&mut rate, 0, Some(Duration::from_millis(30)), |_| true);
+
+        assert_eq!(rate.get_interval(), SendRateConfig::default().min_interval);
+        assert_eq!(rate.loss(), Some(0.0));
+        assert_eq!(rate.get_position_tolerance(), 0.0);
+    }
+
+    #[test]
+    fn test_loss_backs_off_within_bounds() {
+        let config = SendRateConfig::default();
+        let mut rate = SendRate::new(config);
+
+        // Every other snapshot lost
+        for round in 0..10 {
+            send_round(&mut rate, round * ADAPT_EVERY, None, |id| id % 2 == 0);
+        }
+        assert_eq!(rate.get_interval(), config.max_interval);
+        assert!((rate.get_position_tolerance() - config.max_position_tolerance).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_recovers_once_link_is_healthy() {
+        let mut rate = SendRate::default();
+        send_round(&mut rate, 0, Some(Duration::from_millis(400)), |_| true);
+        let backed_off = rate.get_interval();
+        assert!(backed_off > SendRateConfig::default().min_interval);
+
+        send_round(
+            &mut rate,
+            ADAPT_EVERY,
+            Some(Duration::from_millis(30)),
+            |_| true,
+        );
+        assert!(rate.get_interval() < backed_off);
+    }
+
+    #[test]
+    fn test_due_after_interval() {
+        let mut rate = SendRate::default();
+        let start = Instant::now();
+        assert!(rate.is_due(start));
+
+        rate.record_sent(0, start);
+        assert!(!rate.is_due(start + Duration::from_millis(10)));
+        assert!(rate.is_due(start + rate.get_interval()));
+    }
+}
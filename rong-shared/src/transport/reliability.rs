@@ -0,0 +1,575 @@
+use super::sequence_greater_than;
+use crate::model::{ClientMessage, NetworkPacket, ServerMessage};
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+const ACK_WINDOW: u32 = 32;
+const MAX_BUFFERED_MESSAGES: usize = 256;
+
+/*  How a message should travel over the wire */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    // Acked, retransmitted, delivered once and in order
+    Reliable,
+    // Fire and forget, stale packets are dropped in favour of newer ones
+    Sequenced,
+    // Fire and forget, delivered whenever it arrives
+    Unreliable,
+}
+
+pub trait Deliverable {
+    fn delivery(&self) -> Delivery;
+}
+
+impl Deliverable for ClientMessage {
+    fn delivery(&self) -> Delivery {
+        match self {
+            ClientMessage::MovementInput(_) => Delivery::Sequenced,
+            // The client retries the key exchange itself until the server
+            // replies, and every retry gets the same answer
+            ClientMessage::KeyExchange(_) => Delivery::Unreliable,
+            // The encoder keeps the newest ack it has seen, an overtaken one is harmless
+            ClientMessage::SnapshotAck(_) => Delivery::Unreliable,
+            // Every round trip is a sample, a late one still counts
+            ClientMessage::Ping | ClientMessage::Pong(_) => Delivery::Unreliable,
+            // A Disconnect can't wait for an ack from a peer that's already gone,
+            // and mustn't lose to whatever was sent before it
+            ClientMessage::KeepAlive | ClientMessage::Disconnect { .. } => Delivery::Unreliable,
+            _ => Delivery::Reliable,
+        }
+    }
+}
+
+impl Deliverable for ServerMessage {
+    fn delivery(&self) -> Delivery {
+        match self {
+            ServerMessage::GameUpdate(_) => Delivery::Sequenced,
+            ServerMessage::KeyExchange(_)
+            | ServerMessage::Ping
+            | ServerMessage::Pong(_)
+            | ServerMessage::KeepAlive
+            | ServerMessage::Disconnect { .. } => Delivery::Unreliable,
+            _ => Delivery::Reliable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReliabilityConfig {
+    pub resend_timeout: Duration,
+    pub max_resends: u32,
+}
+
+impl Default for ReliabilityConfig {
+    fn default() -> Self {
+        ReliabilityConfig {
+            resend_timeout: Duration::from_millis(100),
+            max_resends: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReliabilityStats {
+    pub sent: u64,
+    pub resent: u64,
+    pub acked: u64,
+    pub duplicates: u64,
+    pub stale: u64,
+    pub expired: u64,
+}
+
+#[derive(Debug, Clone)]
+struct PendingMessage<S> {
+    payload: S,
+    // Every packet sequence this message went out under, any of them acks it
+    sequences: Vec<u32>,
+    last_sent: Instant,
+    resends: u32,
+}
+
+/*  One side of a peer-to-peer link: sends S and receives R */
+#[derive(Debug, Clone)]
+pub struct ReliableEndpoint<S, R> {
+    config: ReliabilityConfig,
+    stats: ReliabilityStats,
+
+    // Outgoing
+    local_sequence: u32,
+    next_reliable_id: u32,
+    pending: BTreeMap<u32, PendingMessage<S>>,
+    // A reliable message ran out of resends. The peer will wait for it forever,
+    // so the link is no good anymore
+    failed: bool,
+
+    // Incoming
+    remote_sequence: Option<u32>,
+    received_bits: u32,
+    latest_unreliable: Option<u32>,
+    next_expected_reliable: u32,
+    buffered: BTreeMap<u32, NetworkPacket<R>>,
+}
+
+impl<S, R> ReliableEndpoint<S, R>
+where
+    S: Deliverable + Clone,
+    R: Deliverable,
+{
+    pub fn new(config: ReliabilityConfig) -> Self {
+        ReliableEndpoint {
+            config,
+            stats: ReliabilityStats::default(),
+            local_sequence: 0,
+            next_reliable_id: 0,
+            pending: BTreeMap::new(),
+            failed: false,
+            remote_sequence: None,
+            received_bits: 0,
+            latest_unreliable: None,
+            next_expected_reliable: 0,
+            buffered: BTreeMap::new(),
+        }
+    }
+
+    // Wrap a payload in a packet, tracking it for retransmission if reliable
+    pub fn send(&mut self, payload: S, timestamp: u64, now: Instant) -> NetworkPacket<S> {
+        self.stats.sent += 1;
+
+        let reliable_id = match payload.delivery() {
+            Delivery::Reliable => {
+                let id = self.next_reliable_id;
+                self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+                Some(id)
+            }
+            Delivery::Sequenced | Delivery::Unreliable => None,
+        };
+
+        let packet = self.build_packet(payload.clone(), reliable_id, timestamp);
+
+        if let Some(id) = reliable_id {
+            self.pending.insert(
+                id,
+                PendingMessage {
+                    payload,
+                    sequences: vec![packet.get_sequence()],
+                    last_sent: now,
+                    resends: 0,
+                },
+            );
+        }
+
+        packet
+    }
+
+    // Process an incoming packet, returning every packet now ready for the application
+    pub fn receive(&mut self, packet: NetworkPacket<R>) -> Vec<NetworkPacket<R>> {
+        let (ack, ack_bits) = packet.get_ack();
+        self.process_acks(ack, ack_bits);
+
+        // Dropped before its sequence is recorded, so it isn't acked and the
+        // peer resends it once there's room
+        if let Some(id) = packet.get_reliable_id() {
+            if self.is_buffer_full_for(id) {
+                return Vec::new();
+            }
+        }
+
+        if !self.record_remote_sequence(packet.get_sequence()) {
+            self.stats.duplicates += 1;
+            return Vec::new();
+        }
+
+        match packet.get_reliable_id() {
+            Some(id) => self.receive_reliable(id, packet),
+            None => self.receive_unreliable(packet),
+        }
+    }
+
+    // Re-send any reliable message that has gone unacknowledged for too long
+    pub fn retransmit(&mut self, timestamp: u64, now: Instant) -> Vec<NetworkPacket<S>> {
+        let config = self.config;
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| pending.resends < config.max_resends);
+        let expired = before - self.pending.len();
+        self.stats.expired += expired as u64;
+        self.failed |= expired > 0;
+
+        let due: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.last_sent) >= config.resend_timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut packets = Vec::with_capacity(due.len());
+        for id in due {
+            let payload = self.pending[&id].payload.clone();
+            let packet = self.build_packet(payload, Some(id), timestamp);
+
+            let pending = self.pending.get_mut(&id).expect("pending message exists");
+            pending.sequences.push(packet.get_sequence());
+            pending.last_sent = now;
+            pending.resends += 1;
+
+            self.stats.resent += 1;
+            packets.push(packet);
+        }
+        packets
+    }
+
+    // True once a reliable message expired unacked. The peer can't get past
+    // the gap it leaves, so the connection should be dropped
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn stats(&self) -> ReliabilityStats {
+        self.stats
+    }
+
+    fn build_packet(
+        &mut self,
+        payload: S,
+        reliable_id: Option<u32>,
+        timestamp: u64,
+    ) -> NetworkPacket<S> {
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        let mut packet = NetworkPacket::new(self.local_sequence, timestamp, payload);
+        if let Some(remote_sequence) = self.remote_sequence {
+            packet.set_ack(remote_sequence, self.received_bits);
+        }
+        packet.set_reliable_id(reliable_id);
+        packet
+    }
+
+    fn process_acks(&mut self, ack: u32, ack_bits: u32) {
+        let is_acked = |sequence: u32| {
+            if sequence == ack {
+                return true;
+            }
+            let distance = ack.wrapping_sub(sequence);
+            (1..=ACK_WINDOW).contains(&distance) && ack_bits & (1 << (distance - 1)) != 0
+        };
+
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| !pending.sequences.iter().any(|s| is_acked(*s)));
+        self.stats.acked += (before - self.pending.len()) as u64;
+    }
+
+    // Returns false if this sequence was already received
+    fn record_remote_sequence(&mut self, sequence: u32) -> bool {
+        let Some(remote_sequence) = self.remote_sequence else {
+            self.remote_sequence = Some(sequence);
+            return true;
+        };
+
+        if sequence_greater_than(sequence, remote_sequence) {
+            let shift = sequence.wrapping_sub(remote_sequence);
+            self.received_bits = if shift > ACK_WINDOW {
+                0
+            } else {
+                // The previous latest sequence becomes bit (shift - 1)
+                ((self.received_bits as u64) << shift | 1 << (shift - 1)) as u32
+            };
+            self.remote_sequence = Some(sequence);
+            return true;
+        }
+
+        let distance = remote_sequence.wrapping_sub(sequence);
+        if distance == 0 {
+            return false;
+        }
+        if distance > ACK_WINDOW {
+            // Too old to track, let the reliable id or staleness check decide
+            return true;
+        }
+
+        let bit = 1 << (distance - 1);
+        if self.received_bits & bit != 0 {
+            return false;
+        }
+        self.received_bits |= bit;
+        true
+    }
+
+    fn receive_reliable(&mut self, id: u32, packet: NetworkPacket<R>) -> Vec<NetworkPacket<R>> {
+        if sequence_greater_than(self.next_expected_reliable, id) {
+            // Already delivered, the peer just missed our ack
+            self.stats.duplicates += 1;
+            return Vec::new();
+        }
+
+        if id != self.next_expected_reliable {
+            // receive already turned it away if there was no room
+            self.buffered.insert(id, packet);
+            return Vec::new();
+        }
+
+        let mut ready = vec![packet];
+        self.next_expected_reliable = self.next_expected_reliable.wrapping_add(1);
+        while let Some(next) = self.buffered.remove(&self.next_expected_reliable) {
+            ready.push(next);
+            self.next_expected_reliable = self.next_expected_reliable.wrapping_add(1);
+        }
+        ready
+    }
+
+    // True if reliable message id would have to wait, and can't
+    fn is_buffer_full_for(&self, id: u32) -> bool {
+        sequence_greater_than(id, self.next_expected_reliable)
+            && self.buffered.len() >= MAX_BUFFERED_MESSAGES
+            && !self.buffered.contains_key(&id)
+    }
+
+    fn receive_unreliable(&mut self, packet: NetworkPacket<R>) -> Vec<NetworkPacket<R>> {
+        // Reliable payloads from peers that don't tag them are passed straight through
+        if packet.get_payload().delivery() != Delivery::Sequenced {
+            return vec![packet];
+        }
+
+        let sequence = packet.get_sequence();
+        if let Some(latest) = self.latest_unreliable {
+            if !sequence_greater_than(sequence, latest) {
+                self.stats.stale += 1;
+                return Vec::new();
+            }
+        }
+        self.latest_unreliable = Some(sequence);
+        vec![packet]
+    }
+}
+
+impl<S, R> Default for ReliableEndpoint<S, R>
+where
+    S: Deliverable + Clone,
+    R: Deliverable,
+{
+    fn default() -> Self {
+        ReliableEndpoint::new(ReliabilityConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        Ack, DisconnectReason, GameSnapshot, GameStatus, GameUpdateData, Movement, MovementData,
+        PositionData, Score, ScoreData, SessionToken,
+    };
+
+    type ServerSide = ReliableEndpoint<ServerMessage, ClientMessage>;
+    type ClientSide = ReliableEndpoint<ClientMessage, ServerMessage>;
+
+    #[test]
+    fn test_reliable_message_is_acked() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let join = client.send(ClientMessage::JoinQueue, 0, now);
+        assert_eq!(client.pending_count(), 1);
+        assert_eq!(server.receive(join).len(), 1);
+
+        // The ack piggybacks on the server's reply
+        let reply = server.send(
+            ServerMessage::Success(Ack::AddedToQueue(SessionToken::new(1))),
+            0,
+            now,
+        );
+        assert_eq!(client.receive(reply).len(), 1);
+        assert_eq!(client.pending_count(), 0, "JoinQueue should be acked");
+    }
+
+    #[test]
+    fn test_lost_message_is_retransmitted_once_delivered() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let _lost = client.send(ClientMessage::JoinQueue, 0, now);
+        assert!(client.retransmit(0, now).is_empty(), "Too early to resend");
+
+        let later = now + Duration::from_millis(150);
+        let resent = client.retransmit(0, later);
+        assert_eq!(resent.len(), 1);
+
+        let duplicate = resent[0].clone();
+        assert_eq!(server.receive(resent[0].clone()).len(), 1);
+        assert!(
+            server.receive(duplicate).is_empty(),
+            "Duplicate should be suppressed"
+        );
+    }
+
+    #[test]
+    fn test_expired_message_fails_the_link() {
+        let config = ReliabilityConfig {
+            resend_timeout: Duration::from_millis(100),
+            max_resends: 2,
+        };
+        let mut client = ClientSide::new(config);
+        let mut now = Instant::now();
+
+        let _lost = client.send(ClientMessage::JoinQueue, 0, now);
+        for _ in 0..config.max_resends {
+            now += config.resend_timeout;
+            assert_eq!(client.retransmit(0, now).len(), 1);
+            assert!(!client.has_failed());
+        }
+
+        now += config.resend_timeout;
+        assert!(client.retransmit(0, now).is_empty());
+        assert_eq!(client.stats().expired, 1);
+        assert!(
+            client.has_failed(),
+            "The server would wait on the lost JoinQueue forever"
+        );
+    }
+
+    #[test]
+    fn test_reliable_messages_are_delivered_in_order() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let first = client.send(ClientMessage::JoinQueue, 0, now);
+        let second = client.send(ClientMessage::LeaveQueue(SessionToken::new(1)), 0, now);
+
+        assert!(
+            server.receive(second).is_empty(),
+            "Should wait for the first message"
+        );
+        let delivered = server.receive(first);
+        assert!(matches!(
+            delivered[0].get_payload(),
+            ClientMessage::JoinQueue
+        ));
+        assert!(matches!(
+            delivered[1].get_payload(),
+            ClientMessage::LeaveQueue(_)
+        ));
+    }
+
+    #[test]
+    fn test_message_without_room_is_not_acked() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let lost = client.send(ClientMessage::JoinQueue, 0, now);
+        let waiting: Vec<_> = (0..MAX_BUFFERED_MESSAGES)
+            .map(|_| client.send(ClientMessage::JoinQueue, 0, now))
+            .collect();
+        let overflow = client.send(ClientMessage::JoinQueue, 0, now);
+        for packet in &waiting {
+            assert!(server.receive(packet.clone()).is_empty());
+        }
+        assert!(server.receive(overflow.clone()).is_empty());
+
+        // Acking it would stop the resends the ordered channel depends on
+        let reply = server.send(ServerMessage::KeepAlive, 0, now);
+        assert_eq!(reply.get_ack().0, waiting.last().unwrap().get_sequence());
+
+        assert_eq!(server.receive(lost).len(), MAX_BUFFERED_MESSAGES + 1);
+        assert_eq!(
+            server.receive(overflow).len(),
+            1,
+            "The resend is delivered once there's room"
+        );
+    }
+
+    #[test]
+    fn test_stale_game_update_is_dropped() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let update = || {
+            ServerMessage::GameUpdate(GameSnapshot::Full {
+                id: 0,
+                data: GameUpdateData::new(
+                    PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5)),
+                    ScoreData::new(Score::default(), Score::default()),
+                    GameStatus::GameStarted,
+                ),
+            })
+        };
+        let older = server.send(update(), 0, now);
+        let newer = server.send(update(), 0, now);
+
+        assert_eq!(client.receive(newer).len(), 1);
+        assert!(client.receive(older).is_empty(), "Older update should lose");
+        assert_eq!(client.stats().stale, 1);
+    }
+
+    #[test]
+    fn test_disconnect_is_never_stale() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let disconnect = client.send(
+            ClientMessage::Disconnect {
+                reason: DisconnectReason::Quit,
+            },
+            0,
+            now,
+        );
+        let input = client.send(
+            ClientMessage::MovementInput(MovementData::new(SessionToken::new(1), 0, Movement::Up)),
+            0,
+            now,
+        );
+
+        assert_eq!(server.receive(input).len(), 1);
+        assert_eq!(
+            server.receive(disconnect).len(),
+            1,
+            "A reordered Disconnect must still arrive"
+        );
+    }
+
+    #[test]
+    fn test_late_pong_still_counts() {
+        let now = Instant::now();
+        let mut client = ClientSide::default();
+        let mut server = ServerSide::default();
+
+        let pong = server.send(ServerMessage::Pong(0), 0, now);
+        let update = server.send(
+            ServerMessage::GameUpdate(GameSnapshot::Full {
+                id: 0,
+                data: GameUpdateData::new(
+                    PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, 0.5)),
+                    ScoreData::new(Score::default(), Score::default()),
+                    GameStatus::GameStarted,
+                ),
+            }),
+            0,
+            now,
+        );
+
+        assert_eq!(client.receive(update).len(), 1);
+        assert_eq!(
+            client.receive(pong).len(),
+            1,
+            "An overtaken Pong is still a round trip sample"
+        );
+        assert_eq!(client.stats().stale, 0);
+    }
+
+    #[test]
+    fn test_sequence_wraparound() {
+        assert!(sequence_greater_than(1, u32::MAX));
+        assert!(!sequence_greater_than(u32::MAX, 1));
+        assert!(!sequence_greater_than(5, 5));
+    }
+}
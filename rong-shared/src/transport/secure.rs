@@ -0,0 +1,487 @@
+use super::WireCodec;
+use crate::error::{CodecError, DecodeError};
+use crate::model::{ClientMessage, NetworkPacket, PublicKey, ServerMessage};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use x25519_dalek::EphemeralSecret;
+
+/*
+    Sealed packet bodies, sitting between the codec and fragmentation:
+
+    | sequence (4, LE) | ChaCha20-Poly1305 ciphertext + tag (16) |
+
+    The nonce is the packet sequence, which the reliability layer never
+    reuses within a session, and each direction has its own key.
+
+    The X25519 keys are ephemeral and nothing vouches for them, so this
+    keeps out eavesdroppers and off-path forgers but not a man in the
+    middle: someone who can rewrite the key exchange can swap in their own
+    key with each side and relay everything. Stopping that would take the
+    client pinning the server's long-term key, which it doesn't.
+*/
+const SEQUENCE_SIZE: usize = 4;
+const TAG_SIZE: usize = 16;
+pub const SEALED_OVERHEAD: usize = SEQUENCE_SIZE + TAG_SIZE;
+
+// How far behind the newest sequence a sealed packet may arrive
+const REPLAY_WINDOW: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/*  Messages that carry the peer's public key in the clear */
+pub trait Handshake {
+    fn key_exchange(&self) -> Option<PublicKey>;
+}
+
+impl Handshake for ClientMessage {
+    fn key_exchange(&self) -> Option<PublicKey> {
+        match self {
+            ClientMessage::KeyExchange(key) => Some(*key),
+            _ => None,
+        }
+    }
+}
+
+impl Handshake for ServerMessage {
+    fn key_exchange(&self) -> Option<PublicKey> {
+        match self {
+            ServerMessage::KeyExchange(key) => Some(*key),
+            _ => None,
+        }
+    }
+}
+
+/*  Our half of the key exchange, kept until the peer's public key arrives */
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        KeyExchange { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    pub fn finish(self, role: Role, remote_key: PublicKey) -> Result<SecureChannel, DecodeError> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(remote_key));
+        if !shared.was_contributory() {
+            return Err(DecodeError::Malformed("Low order public key".to_string()));
+        }
+
+        // Both sides must feed the keys in the same order
+        let (client_key, server_key) = match role {
+            Role::Client => (self.public_key, remote_key),
+            Role::Server => (remote_key, self.public_key),
+        };
+        let mut salt = [0; 64];
+        salt[..32].copy_from_slice(&client_key);
+        salt[32..].copy_from_slice(&server_key);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let derive = |info: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(info, &mut key)
+                .expect("32 bytes is a valid HKDF-SHA256 output length");
+            ChaCha20Poly1305::new(Key::from_slice(&key))
+        };
+        let client_to_server = derive(b"rong client to server");
+        let server_to_client = derive(b"rong server to client");
+
+        let (sealer, opener) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+
+        Ok(SecureChannel {
+            sealer,
+            opener,
+            local_key: self.public_key,
+            remote_key,
+            replay: ReplayWindow::default(),
+        })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*  What came out of a datagram body on a secure link */
+#[derive(Debug)]
+pub enum Opened<T> {
+    Packet(NetworkPacket<T>),
+    KeyExchange(PublicKey),
+}
+
+// Before a channel exists everything is plaintext
+pub fn open_plain<C: WireCodec, T: DeserializeOwned + Handshake>(
+    body: &[u8],
+) -> Result<Opened<T>, DecodeError> {
+    let packet: NetworkPacket<T> = C::decode(body)?;
+    Ok(match packet.get_payload().key_exchange() {
+        Some(key) => Opened::KeyExchange(key),
+        None => Opened::Packet(packet),
+    })
+}
+
+/*  One side of an established session */
+#[derive(Clone)]
+pub struct SecureChannel {
+    sealer: ChaCha20Poly1305,
+    opener: ChaCha20Poly1305,
+    local_key: PublicKey,
+    remote_key: PublicKey,
+    replay: ReplayWindow,
+}
+
+impl SecureChannel {
+    pub fn local_key(&self) -> PublicKey {
+        self.local_key
+    }
+
+    pub fn remote_key(&self) -> PublicKey {
+        self.remote_key
+    }
+
+    pub fn seal<C: WireCodec, T: Serialize>(
+        &self,
+        packet: &NetworkPacket<T>,
+    ) -> Result<Vec<u8>, CodecError> {
+        let sequence = packet.get_sequence();
+        let ciphertext = self
+            .sealer
+            .encrypt(&nonce(sequence), C::encode(packet)?.as_slice())
+            .map_err(|_| CodecError::Encode("Failed to seal packet".to_string()))?;
+
+        let mut sealed = Vec::with_capacity(SEQUENCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&sequence.to_le_bytes());
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // Only a repeated key exchange is let through unsealed, and it can't
+    // change anything about the session
+    pub fn open<C: WireCodec, T: DeserializeOwned + Handshake>(
+        &mut self,
+        body: &[u8],
+    ) -> Result<Opened<T>, DecodeError> {
+        match self.open_sealed::<C, T>(body) {
+            Ok(packet) => Ok(Opened::Packet(packet)),
+            Err(e) => match C::decode::<NetworkPacket<T>>(body) {
+                Ok(packet) => match packet.get_payload().key_exchange() {
+                    Some(key) => Ok(Opened::KeyExchange(key)),
+                    None => Err(e),
+                },
+                Err(_) => Err(e),
+            },
+        }
+    }
+
+    fn open_sealed<C: WireCodec, T: DeserializeOwned>(
+        &mut self,
+        body: &[u8],
+    ) -> Result<NetworkPacket<T>, DecodeError> {
+        if body.len() < SEALED_OVERHEAD {
+            return Err(DecodeError::Truncated);
+        }
+
+        let (sequence, ciphertext) = body.split_at(SEQUENCE_SIZE);
+        let sequence = u32::from_le_bytes([sequence[0], sequence[1], sequence[2], sequence[3]]);
+        if !self.replay.is_fresh(sequence) {
+            return Err(DecodeError::Replayed(sequence));
+        }
+
+        let plaintext = self
+            .opener
+            .decrypt(&nonce(sequence), ciphertext)
+            .map_err(|_| DecodeError::Unauthenticated)?;
+        let packet: NetworkPacket<T> = C::decode(&plaintext)?;
+        if packet.get_sequence() != sequence {
+            return Err(DecodeError::Malformed(
+                "Sealed sequence doesn't match the packet".to_string(),
+            ));
+        }
+
+        // Only authenticated packets move the window
+        self.replay.record(sequence);
+        Ok(packet)
+    }
+}
+
+fn nonce(sequence: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..SEQUENCE_SIZE].copy_from_slice(&sequence.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/*  Sliding window of recently opened sequences */
+#[derive(Debug, Clone, Copy, Default)]
+struct ReplayWindow {
+    latest: Option<u32>,
+    // Bit n set means latest - (n + 1) was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, sequence: u32) -> bool {
+        let Some(latest) = self.latest else {
+            return true;
+        };
+        if super::sequence_greater_than(sequence, latest) {
+            return true;
+        }
+
+        let distance = latest.wrapping_sub(sequence);
+        distance != 0 && distance <= REPLAY_WINDOW && self.seen & (1 << (distance - 1)) == 0
+    }
+
+    fn record(&mut self, sequence: u32) {
+        let Some(latest) = self.latest else {
+            self.latest = Some(sequence);
+            return;
+        };
+
+        if super::sequence_greater_than(sequence, latest) {
+            let shift = sequence.wrapping_sub(latest);
+            self.seen = if shift > REPLAY_WINDOW {
+                0
+            } else {
+                self.seen.checked_shl(shift).unwrap_or(0) | 1 << (shift - 1)
+            };
+            self.latest = Some(sequence);
+        } else {
+            self.seen |= 1 << (latest.wrapping_sub(sequence) - 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PROTOCOL_VERSION;
+    use crate::transport::{DefaultCodec, Fragmenter, Reassembler, RECEIVE_BUFFER_SIZE};
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    fn handshake() -> (SecureChannel, SecureChannel) {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let server_key = server.public_key();
+        (
+            client.finish(Role::Client, server_key).unwrap(),
+            server.finish(Role::Server, client_key).unwrap(),
+        )
+    }
+
+    fn hello(sequence: u32) -> NetworkPacket<ClientMessage> {
+        NetworkPacket::new(
+            sequence,
+            0,
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_build: "test".to_string(),
+            },
+        )
+    }
+
+    fn open(
+        channel: &mut SecureChannel,
+        body: &[u8],
+    ) -> Result<Opened<ClientMessage>, DecodeError> {
+        channel.open::<DefaultCodec, ClientMessage>(body)
+    }
+
+    #[test]
+    fn test_key_exchange_over_loopback() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket
+            .connect(server_socket.local_addr().unwrap())
+            .unwrap();
+        server_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client_socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut client_fragmenter = Fragmenter::new();
+        let mut server_fragmenter = Fragmenter::new();
+        let mut server_reassembler = Reassembler::new();
+        let mut client_reassembler = Reassembler::new();
+        let mut buf = [0; RECEIVE_BUFFER_SIZE];
+
+        // Client sends its public key in the clear
+        let client_exchange = KeyExchange::new();
+        let packet = NetworkPacket::new(
+            1,
+            0,
+            ClientMessage::KeyExchange(client_exchange.public_key()),
+        );
+        for datagram in client_fragmenter
+            .encode::<DefaultCodec, _>(&packet)
+            .unwrap()
+        {
+            client_socket.send(&datagram).unwrap();
+        }
+
+        let (size, client_addr) = server_socket.recv_from(&mut buf).unwrap();
+        let body = server_reassembler
+            .receive(&buf[..size], Instant::now())
+            .unwrap()
+            .unwrap();
+        let Opened::KeyExchange(client_key) =
+            open_plain::<DefaultCodec, ClientMessage>(&body).unwrap()
+        else {
+            panic!("Expected a key exchange");
+        };
+
+        // Server answers with its own and switches to sealed traffic
+        let server_exchange = KeyExchange::new();
+        let reply = NetworkPacket::new(
+            1,
+            0,
+            ServerMessage::KeyExchange(server_exchange.public_key()),
+        );
+        for datagram in server_fragmenter.encode::<DefaultCodec, _>(&reply).unwrap() {
+            server_socket.send_to(&datagram, client_addr).unwrap();
+        }
+        let mut server_channel = server_exchange.finish(Role::Server, client_key).unwrap();
+
+        let size = client_socket.recv(&mut buf).unwrap();
+        let body = client_reassembler
+            .receive(&buf[..size], Instant::now())
+            .unwrap()
+            .unwrap();
+        let Opened::KeyExchange(server_key) =
+            open_plain::<DefaultCodec, ServerMessage>(&body).unwrap()
+        else {
+            panic!("Expected a key exchange");
+        };
+        let client_channel = client_exchange.finish(Role::Client, server_key).unwrap();
+
+        // A sealed packet large enough to need fragmenting
+        let mut packet = hello(2);
+        packet.set_payload(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "x".repeat(3000),
+        });
+        let sealed = client_channel.seal::<DefaultCodec, _>(&packet).unwrap();
+        for datagram in client_fragmenter.encode_body(&sealed).unwrap() {
+            client_socket.send(&datagram).unwrap();
+        }
+
+        let mut received = None;
+        while received.is_none() {
+            let size = server_socket.recv(&mut buf).unwrap();
+            received = server_reassembler
+                .receive(&buf[..size], Instant::now())
+                .unwrap();
+        }
+        let Opened::Packet(packet) = open(&mut server_channel, &received.unwrap()).unwrap() else {
+            panic!("Expected a sealed packet");
+        };
+        assert_eq!(packet.get_sequence(), 2);
+    }
+
+    #[test]
+    fn test_replayed_packet_is_rejected() {
+        let (client, mut server) = handshake();
+        let sealed = client.seal::<DefaultCodec, _>(&hello(7)).unwrap();
+
+        assert!(matches!(open(&mut server, &sealed), Ok(Opened::Packet(_))));
+        assert!(matches!(
+            open(&mut server, &sealed),
+            Err(DecodeError::Replayed(7))
+        ));
+    }
+
+    #[test]
+    fn test_out_of_order_packets_within_window_are_accepted() {
+        let (client, mut server) = handshake();
+        let late = client.seal::<DefaultCodec, _>(&hello(90)).unwrap();
+        let early = client.seal::<DefaultCodec, _>(&hello(100)).unwrap();
+        let ancient = client.seal::<DefaultCodec, _>(&hello(40)).unwrap();
+
+        assert!(open(&mut server, &early).is_ok());
+        assert!(open(&mut server, &late).is_ok());
+        assert!(
+            open(&mut server, &ancient).is_ok(),
+            "Still within the replay window"
+        );
+
+        let too_old = client
+            .seal::<DefaultCodec, _>(&hello(100 - REPLAY_WINDOW - 1))
+            .unwrap();
+        assert!(matches!(
+            open(&mut server, &too_old),
+            Err(DecodeError::Replayed(_))
+        ));
+    }
+
+    #[test]
+    fn test_tampered_packet_is_rejected() {
+        let (client, mut server) = handshake();
+        let mut sealed = client.seal::<DefaultCodec, _>(&hello(3)).unwrap();
+        *sealed.last_mut().unwrap() ^= 0x01;
+
+        assert!(matches!(
+            open(&mut server, &sealed),
+            Err(DecodeError::Unauthenticated)
+        ));
+        // A failed packet doesn't burn its sequence
+        let genuine = client.seal::<DefaultCodec, _>(&hello(3)).unwrap();
+        assert!(open(&mut server, &genuine).is_ok());
+    }
+
+    #[test]
+    fn test_plaintext_is_rejected_once_secure() {
+        let (_, mut server) = handshake();
+        let plain = DefaultCodec::encode(&hello(4)).unwrap();
+        assert!(open(&mut server, &plain).is_err());
+
+        // Except a repeated key exchange
+        let exchange = DefaultCodec::encode(&NetworkPacket::new(
+            5,
+            0,
+            ClientMessage::KeyExchange([9; 32]),
+        ))
+        .unwrap();
+        assert!(matches!(
+            open(&mut server, &exchange),
+            Ok(Opened::KeyExchange([9, ..]))
+        ));
+    }
+
+    #[test]
+    fn test_reflected_packet_is_rejected() {
+        let (client, _) = handshake();
+        let mut reflected = client.clone();
+        let sealed = client.seal::<DefaultCodec, _>(&hello(6)).unwrap();
+
+        // Each direction has its own key, so a packet can't be bounced back
+        assert!(matches!(
+            open(&mut reflected, &sealed),
+            Err(DecodeError::Unauthenticated)
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use super::sequence_greater_than;
+use crate::model::{GameSnapshot, GameUpdateData, GameUpdateDelta};
+
+use std::collections::VecDeque;
+
+// Snapshots older than this can't be used as a baseline anymore
+const SNAPSHOT_HISTORY: usize = 32;
+
+/*  Server side, one per client: encodes updates against the last acked snapshot */
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotEncoder {
+    next_id: u32,
+    acked: Option<u32>,
+    history: VecDeque<(u32, GameUpdateData)>,
+}
+
+impl SnapshotEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, data: GameUpdateData) -> GameSnapshot {
+        self.encode_within(data, 0.0)
+    }
+
+    // Like encode, but positions that all moved less than tolerance since the
+    // baseline aren't resent. The client keeps the baseline's, so that's also
+    // what gets remembered as sent
+    pub fn encode_within(&mut self, data: GameUpdateData, tolerance: f32) -> GameSnapshot {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let baseline = self
+            .acked
+            .and_then(|acked| self.history.iter().find(|(id, _)| *id == acked));
+
+        let data = match baseline {
+            Some((_, baseline))
+                if tolerance > 0.0
+                    && data
+                        .get_positions()
+                        .is_within(baseline.get_positions(), tolerance) =>
+            {
+                data.with_positions(baseline.get_positions().clone())
+            }
+            _ => data,
+        };
+
+        let snapshot = match baseline {
+            Some((baseline_id, baseline)) => {
+                GameSnapshot::Delta(GameUpdateDelta::between(id, *baseline_id, baseline, &data))
+            }
+            // Nothing acked yet, or the ack fell out of history
+            None => GameSnapshot::Full {
+                id,
+                data: data.clone(),
+            },
+        };
+
+        if self.history.len() == SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((id, data));
+
+        snapshot
+    }
+
+    pub fn acknowledge(&mut self, id: u32) {
+        if let Some(acked) = self.acked {
+            if !sequence_greater_than(id, acked) {
+                return;
+            }
+        }
+        self.acked = Some(id);
+
+        // Anything older than the new baseline will never be needed again
+        self.history
+            .retain(|(snapshot_id, _)| !sequence_greater_than(id, *snapshot_id));
+    }
+}
+
+/*  Client side: rebuilds full updates from deltas */
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDecoder {
+    latest: Option<u32>,
+    history: VecDeque<(u32, GameUpdateData)>,
+}
+
+impl SnapshotDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns None for stale snapshots and deltas whose baseline we no longer have
+    pub fn decode(&mut self, snapshot: GameSnapshot) -> Option<(u32, GameUpdateData)> {
+        let id = snapshot.get_id();
+        if let Some(latest) = self.latest {
+            if !sequence_greater_than(id, latest) {
+                return None;
+            }
+        }
+
+        let data = match snapshot {
+            GameSnapshot::Full { data, .. } => data,
+            GameSnapshot::Delta(delta) => {
+                let (_, baseline) = self
+                    .history
+                    .iter()
+                    .find(|(id, _)| *id == delta.get_baseline_id())?;
+                delta.apply(baseline)
+            }
+        };
+
+        self.latest = Some(id);
+        if self.history.len() == SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((id, data.clone()));
+
+        Some((id, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{GameStatus, PlayerId, PositionData, Score, ScoreData};
+    use crate::transport::{DefaultCodec, WireCodec};
+
+    fn update(ball_y: f32) -> GameUpdateData {
+        GameUpdateData::new(
+            PositionData::new((0.5, 0.1), (0.5, 0.9), (0.5, ball_y)),
+            ScoreData::new(Score::default(), Score::default()),
+            GameStatus::GameStarted,
+        )
+    }
+
+    #[test]
+    fn test_full_snapshot_until_acked() {
+        let mut encoder = SnapshotEncoder::new();
+        assert!(matches!(
+            encoder.encode(update(0.5)),
+            GameSnapshot::Full { .. }
+        ));
+        assert!(matches!(
+            encoder.encode(update(0.6)),
+            GameSnapshot::Full { .. }
+        ));
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let (id, _) = decoder.decode(encoder.encode(update(0.5))).unwrap();
+        encoder.acknowledge(id);
+
+        let snapshot = encoder.encode(update(0.6));
+        assert!(matches!(snapshot, GameSnapshot::Delta(_)));
+
+        let (_, data) = decoder.decode(snapshot).unwrap();
+        assert_eq!(data, update(0.6));
+    }
+
+    #[test]
+    fn test_delta_carries_last_inputs() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let (id, _) = decoder.decode(encoder.encode(update(0.5))).unwrap();
+        encoder.acknowledge(id);
+
+        let applied = update(0.5).with_last_inputs(vec![(PlayerId::PLAYER_1, 7)]);
+        let (_, data) = decoder.decode(encoder.encode(applied)).unwrap();
+        assert_eq!(data.get_last_input(PlayerId::PLAYER_1), Some(7));
+        assert_eq!(data.get_last_input(PlayerId::PLAYER_2), None);
+    }
+
+    #[test]
+    fn test_delta_without_baseline_is_dropped() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let first = encoder.encode(update(0.5));
+        encoder.acknowledge(first.get_id());
+
+        // The client never saw the baseline
+        assert!(decoder.decode(encoder.encode(update(0.6))).is_none());
+    }
+
+    #[test]
+    fn test_small_moves_within_tolerance_are_held_back() {
+        let mut encoder = SnapshotEncoder::new();
+        let mut decoder = SnapshotDecoder::new();
+
+        let (id, _) = decoder.decode(encoder.encode(update(0.5))).unwrap();
+        encoder.acknowledge(id);
+
+        let (id, data) = decoder
+            .decode(encoder.encode_within(update(0.505), 0.01))
+            .unwrap();
+        assert_eq!(data, update(0.5));
+        encoder.acknowledge(id);
+
+        // Measured against what the client holds, not what was last simulated
+        let (_, data) = decoder
+            .decode(encoder.encode_within(update(0.52), 0.01))
+            .unwrap();
+        assert_eq!(data, update(0.52));
+    }
+
+    #[test]
+    fn test_delta_only_carries_what_moved() {
+        let mut encoder = SnapshotEncoder::new();
+        let full = encoder.encode(update(0.5));
+        encoder.acknowledge(full.get_id());
+
+        // Only the ball moves, the paddles stay out of the delta
+        let delta = encoder.encode(update(0.6));
+        assert!(matches!(delta, GameSnapshot::Delta(_)));
+        let full_size = DefaultCodec::encode(&full).unwrap().len();
+        let delta_size = DefaultCodec::encode(&delta).unwrap().len();
+        assert!(
+            delta_size < full_size,
+            "Delta is {} bytes, the full update {}",
+            delta_size,
+            full_size
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use super::{LinkSimulator, NetworkConditions};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+
+// Where MemoryNetwork starts handing out ports for binds to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/*
+    Moves datagrams between addresses, the part of the server that touches the network.
+
+    Code generic over this runs the same on a real UDP socket and on a
+    MemoryNetwork, which needs no ports and delivers every datagram in order.
+*/
+pub trait Transport: Send + Sync + 'static {
+    fn send_to(
+        &self,
+        datagram: &[u8],
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    // Datagrams longer than buf are truncated, as with UDP
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    async fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, datagram, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+type Inbox = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+
+/*  A pretend network, every MemoryTransport bound on it can reach the others */
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    endpoints: Arc<std::sync::Mutex<HashMap<SocketAddr, Inbox>>>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        MemoryNetwork {
+            endpoints: Arc::default(),
+            next_port: Arc::new(AtomicU16::new(FIRST_EPHEMERAL_PORT)),
+        }
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Port 0 picks a free port, like binding a real socket
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+
+        let mut addr = addr;
+        if addr.port() == 0 {
+            loop {
+                addr.set_port(self.next_port.fetch_add(1, Ordering::Relaxed));
+                if addr.port() != 0 && !endpoints.contains_key(&addr) {
+                    break;
+                }
+            }
+        } else if endpoints.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        }
+
+        let (sender, inbox) = mpsc::unbounded_channel();
+        endpoints.insert(addr, sender);
+        Ok(MemoryTransport {
+            addr,
+            network: self.clone(),
+            inbox: Mutex::new(inbox),
+        })
+    }
+
+    // Shorthand for a localhost endpoint on a free port
+    pub fn bind_any(&self) -> io::Result<MemoryTransport> {
+        self.bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    }
+}
+
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Transport for MemoryTransport {
+    // Datagrams to an address nobody bound vanish, as they would over UDP
+    async fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let endpoints = self.network.endpoints.lock().unwrap();
+        if let Some(inbox) = endpoints.get(&addr) {
+            let _ = inbox.send((datagram.to_vec(), self.addr));
+        }
+        Ok(datagram.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut inbox = self.inbox.lock().await;
+        // Our own sender sits in the network until we drop, so this only ends with us
+        let (datagram, from) = inbox.recv().await.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "Memory network closed")
+        })?;
+        let size = datagram.len().min(buf.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut endpoints) = self.network.endpoints.lock() {
+            endpoints.remove(&self.addr);
+        }
+    }
+}
+
+/*
+    Wraps another transport and sends through a LinkSimulator, so datagrams
+    arrive late, twice, out of order or not at all. Receiving is untouched.
+*/
+pub struct SimulatedTransport<T: Transport> {
+    inner: Arc<T>,
+    link: std::sync::Mutex<LinkSimulator>,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, conditions: NetworkConditions) -> Self {
+        SimulatedTransport::with_link(inner, LinkSimulator::new(conditions))
+    }
+
+    pub fn with_link(inner: T, link: LinkSimulator) -> Self {
+        SimulatedTransport {
+            inner: Arc::new(inner),
+            link: std::sync::Mutex::new(link),
+        }
+    }
+
+    pub fn get_conditions(&self) -> NetworkConditions {
+        self.link.lock().unwrap().get_conditions()
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    // Reports the datagram as sent even when the simulated network drops it,
+    // like a real socket would
+    async fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let delays = self.link.lock().unwrap().delays();
+        for delay in delays {
+            if delay.is_zero() {
+                self.inner.send_to(datagram, addr).await?;
+                continue;
+            }
+            let inner = self.inner.clone();
+            let datagram = datagram.to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = inner.send_to(&datagram, addr).await;
+            });
+        }
+        Ok(datagram.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_round_trip() {
+        let network = MemoryNetwork::new();
+        let server = network.bind("10.0.0.1:2906".parse().unwrap()).unwrap();
+        let client = network.bind_any().unwrap();
+
+        client
+            .send_to(b"hello", server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (size, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(from, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unbound_address_drops_datagram() {
+        let network = MemoryNetwork::new();
+        let client = network.bind_any().unwrap();
+        let nobody = "10.0.0.9:1".parse().unwrap();
+
+        assert_eq!(client.send_to(b"lost", nobody).await.unwrap(), 4);
+    }
+
+    #[test]
+    fn test_address_in_use() {
+        let network = MemoryNetwork::new();
+        let addr = "10.0.0.1:2906".parse().unwrap();
+        let first = network.bind(addr).unwrap();
+
+        assert_eq!(
+            network.bind(addr).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+        drop(first);
+        assert!(network.bind(addr).is_ok(), "Port should be free again");
+    }
+
+    #[tokio::test]
+    async fn test_simulated_latency_and_loss() {
+        let network = MemoryNetwork::new();
+        let server = network.bind_any().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let latency = std::time::Duration::from_millis(30);
+
+        let slow = SimulatedTransport::new(
+            network.bind_any().unwrap(),
+            NetworkConditions {
+                latency,
+                ..Default::default()
+            },
+        );
+        let start = tokio::time::Instant::now();
+        slow.send_to(b"late", server_addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (size, _) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"late");
+        assert!(start.elapsed() >= latency);
+
+        let lossy = SimulatedTransport::new(
+            network.bind_any().unwrap(),
+            NetworkConditions {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+        assert_eq!(lossy.send_to(b"lost", server_addr).await.unwrap(), 4);
+        let received = tokio::time::timeout(latency, server.recv_from(&mut buf)).await;
+        assert!(received.is_err(), "Lost datagram arrived");
+    }
+}
@@ -1,29 +1,42 @@
-use bincode;
-use rong_server::network::GameServer;
-use rong_shared::model::{ClientMessage, NetworkPacket, PlayerId, ServerMessage};
+use rong_server::game_server::GameServer;
+use rong_shared::model::{ClientMessage, NetworkPacket, ServerMessage, PROTOCOL_VERSION};
+use rong_shared::transport::{
+    decode_datagram, encode_datagram, DefaultCodec, NetworkConditions, RECEIVE_BUFFER_SIZE,
+};
 use std::io;
 use tokio::net::UdpSocket;
 use tokio::time::Duration;
 
-const SERVER_ADDR: &str = "127.0.0.1:2906";
-
 #[tokio::test]
 async fn test_basic_connection() -> io::Result<()> {
     println!("Starting basic connection test");
 
+    // A real server on a free port, so tests don't fight over 2906
+    let server =
+        GameServer::new("127.0.0.1:0".parse().unwrap(), NetworkConditions::default()).await?;
+    let server_addr = server.local_addr()?;
+    let shutdown = server.get_shutdown_handle();
+    let running = tokio::spawn(server.run());
+
     // Create a mock client
-    let client_socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let client_socket = UdpSocket::bind("127.0.0.1:0").await?;
     println!("Client socket bound to {}", client_socket.local_addr()?);
 
-    // Send connect message
-    let connect_message = NetworkPacket::new(1, 0, ClientMessage::Connect());
-    let serialized = bincode::serialize(&connect_message).expect("Failed to serialize message");
-    println!("Sending bytes: {:?}", serialized);
-    client_socket.send_to(&serialized, SERVER_ADDR).await?;
-    println!("Connect message sent");
+    // Send hello message
+    let hello = NetworkPacket::new(
+        1,
+        0,
+        ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "integration".to_string(),
+        },
+    );
+    let serialized = encode_datagram::<DefaultCodec, _>(&hello)?;
+    client_socket.send_to(&serialized, server_addr).await?;
+    println!("Hello message sent");
 
-    // Wait for PlayerJoined response
-    let mut buf = [0; 1024];
+    // Wait for Welcome response
+    let mut buf = [0; RECEIVE_BUFFER_SIZE];
     let result =
         tokio::time::timeout(Duration::from_secs(5), client_socket.recv_from(&mut buf)).await;
 
@@ -31,17 +44,15 @@ async fn test_basic_connection() -> io::Result<()> {
         Ok(Ok((size, addr))) => {
             println!("Received {} bytes from {}", size, addr);
             let response: NetworkPacket<ServerMessage> =
-                bincode::deserialize(&buf[..size]).expect("Failed to deserialize response");
+                decode_datagram::<DefaultCodec, _>(&buf[..size])
+                    .expect("Failed to decode response");
 
             match response.get_payload() {
-                ServerMessage::PlayerJoined(player_id) => {
-                    assert!(
-                        *player_id == PlayerId::Player1 || *player_id == PlayerId::Player2,
-                        "Received invalid player ID"
-                    );
-                    println!("Received PlayerJoined with ID: {:?}", player_id);
+                ServerMessage::Welcome { protocol_version } => {
+                    assert_eq!(*protocol_version, PROTOCOL_VERSION);
+                    println!("Received Welcome for protocol {}", protocol_version);
                 }
-                _ => panic!("Expected PlayerJoined message, got {:?}", response),
+                _ => panic!("Expected Welcome message, got {:?}", response),
             }
         }
         Ok(Err(e)) => {
@@ -57,6 +68,8 @@ async fn test_basic_connection() -> io::Result<()> {
         }
     }
 
+    shutdown.shutdown();
+    running.await.expect("server task panicked")?;
     println!("Basic connection test passed successfully");
     Ok(())
 }